@@ -0,0 +1,74 @@
+// ddstats-rust overlay, see src/overlay.rs for the supported query string
+(function () {
+    const widget = document.body.dataset.widget;
+    const params = new URLSearchParams(window.location.search);
+    const root = document.documentElement.style;
+    const el = document.getElementById("overlay");
+
+    const showLabel = params.get("label") !== "false";
+    const precision = parseInt(params.get("precision") || "4", 10);
+    const rows = parseInt(params.get("rows") || "0", 10);
+
+    if (params.has("size")) root.setProperty("--size", params.get("size") + "px");
+    if (params.has("font")) root.setProperty("--font", params.get("font"));
+    if (params.has("align")) root.setProperty("--align", params.get("align"));
+    if (params.has("bg")) root.setProperty("--bg", color(params.get("bg")));
+    for (const name of ["text", "accent", "pos", "neg", "neutral", "gold"]) {
+        if (params.has(name)) root.setProperty("--" + name, color(params.get(name)));
+    }
+
+    function color(value) {
+        return /^[0-9a-fA-F]{3,8}$/.test(value) ? "#" + value : value;
+    }
+
+    function escape(text) {
+        return String(text).replace(/[&<>"]/g, c => ({ "&": "&amp;", "<": "&lt;", ">": "&gt;", "\"": "&quot;" }[c]));
+    }
+
+    function label(text) {
+        return showLabel ? `<span class="label">${text}</span>` : "";
+    }
+
+    function signed(n) {
+        return n > 0 ? "+" + n : String(n);
+    }
+
+    const renderers = {
+        "timer": d => `${label("TIMER")}<span class="value">${d.time.toFixed(precision)}</span><span class="unit">s</span>`,
+        "homing": d => `${label("HOMING")}<span class="value">${d.homing}</span>`
+            + `<span class="detail"> [<span class="value">${d.max_homing}</span> at <span class="value">${d.max_homing_time.toFixed(precision)}</span>s]</span>`,
+        "gems-lost": d => `${label("GEMS LOST")}<span class="value">${d.gems_lost}</span>`
+            + `<span class="detail"> [<span class="value">${d.gems_despawned}</span> despawned, <span class="value">${d.gems_eaten}</span> eaten]</span>`,
+        "splits": d => {
+            let splits = d.splits;
+            if (rows > 0) {
+                const live = Math.max(0, splits.findIndex(s => s.live));
+                splits = splits.slice(Math.max(0, live - rows + 1), Math.max(0, live - rows + 1) + rows);
+            }
+            const body = splits.map(s => {
+                const cls = s.live ? "live" : (s.reached ? "" : "pending");
+                const homing = s.homing === null ? "...." : s.homing;
+                const diff = s.diff === null ? "-" : signed(s.diff);
+                const kind = s.kind === null ? "" : s.kind.toLowerCase().replace("positive", "pos").replace("negative", "neg");
+                return `<tr class="${cls}"><td class="name">${escape(s.name)}</td><td class="value">${homing}</td><td class="${kind}">${diff}</td></tr>`;
+            }).join("");
+            const title = showLabel && d.profile ? `<div class="label">${escape(d.profile)}</div>` : "";
+            return `${title}<table>${body}</table>`;
+        },
+    };
+
+    const render = renderers[widget];
+    const forwarded = new URLSearchParams();
+    for (const name of ["compare", "token"]) {
+        if (params.has(name)) forwarded.set(name, params.get(name));
+    }
+    const query = forwarded.toString() ? "?" + forwarded.toString() : "";
+
+    function connect() {
+        const source = new EventSource("/overlay/data" + query);
+        source.onmessage = ev => { el.innerHTML = render(JSON.parse(ev.data)); };
+        source.onerror = () => { source.close(); setTimeout(connect, 2000); };
+    }
+
+    connect();
+})();
@@ -32,7 +32,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     tonic_build::configure()
         .build_server(false)
-        .compile(&["proto/ddstats.proto"], &["proto"])?;
+        .compile(&["proto/ddstats.proto", "proto/history.proto"], &["proto"])?;
 
     Ok(())
 }
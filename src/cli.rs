@@ -0,0 +1,228 @@
+//
+//  cli.rs - Command line interface
+//
+
+use std::sync::Arc;
+use clap::{App, AppSettings, Arg, ArgMatches};
+use ddcore_rs::models::StatsFrame;
+use crate::replay_recv::StatusReply;
+use crate::replay_upload::UploadError;
+use crate::run_history::RunSummary;
+
+pub enum CliCommand {
+    Run { replay: Option<String>, headless: bool },
+    Upload(String),
+    Config(ConfigCommand),
+    Status,
+    Control(&'static str),
+    Export { output: Option<String>, frames: bool },
+    Schema,
+}
+
+pub enum ConfigCommand {
+    Validate,
+    Print,
+    Path,
+    Reset,
+}
+
+pub fn app() -> App<'static> {
+    App::new("ddstats-rust")
+        .bin_name("ddstats-rust")
+        .version(crate::consts::PKG_VERSION)
+        .setting(AppSettings::ArgsNegateSubcommands)
+        .arg(Arg::new("replay")
+            .takes_value(true)
+            .value_name("FILE")
+            .required(false)
+            .help("Opens replay with ddstats-rust"))
+        .arg(Arg::new("headless")
+            .long("headless")
+            .global(true)
+            .takes_value(false)
+            .help("Runs without the terminal UI, logging plain lines to stdout"))
+        .subcommand(App::new("play")
+            .about("Plays a replay file (in the running instance if there is one)")
+            .arg(Arg::new("file").takes_value(true).value_name("FILE").required(true)))
+        .subcommand(App::new("upload")
+            .about("Uploads a replay file")
+            .arg(Arg::new("file").takes_value(true).value_name("FILE").required(true)))
+        .subcommand(App::new("config")
+            .about("Inspects or resets the config file")
+            .setting(AppSettings::SubcommandRequiredElseHelp)
+            .subcommand(App::new("validate").about("Checks that the config file parses"))
+            .subcommand(App::new("print").about("Prints the config currently in use"))
+            .subcommand(App::new("path").about("Prints the path of the config file"))
+            .subcommand(App::new("reset").about("Replaces the config file with the default, keeping a backup")))
+        .subcommand(App::new("status")
+            .about("Shows the status of the running instance"))
+        .subcommand(App::new("show").about("Shows the window of the running instance"))
+        .subcommand(App::new("hide").about("Hides the window of the running instance"))
+        .subcommand(App::new("reload").about("Makes the running instance reload its config file"))
+        .subcommand(App::new("quit").about("Closes the running instance"))
+        .subcommand(App::new("export")
+            .about("Exports the local run history as JSON")
+            .arg(Arg::new("output")
+                .short('o')
+                .long("output")
+                .takes_value(true)
+                .value_name("FILE")
+                .help("Writes to a file instead of stdout"))
+            .arg(Arg::new("frames")
+                .long("frames")
+                .takes_value(false)
+                .help("Includes the per second stats frames of every run")))
+        .subcommand(App::new("schema")
+            .about("Prints the JSON schema of the websocket protocol"))
+}
+
+pub fn parse() -> CliCommand {
+    let matches = app().get_matches();
+    let headless = |sub: &ArgMatches| matches.is_present("headless") || sub.is_present("headless");
+
+    match matches.subcommand() {
+        Some(("play", sub)) => CliCommand::Run {
+            replay: sub.value_of("file").map(absolute),
+            headless: headless(sub),
+        },
+        Some(("upload", sub)) => CliCommand::Upload(absolute(sub.value_of("file").unwrap())),
+        Some(("config", sub)) => CliCommand::Config(match sub.subcommand_name() {
+            Some("validate") => ConfigCommand::Validate,
+            Some("print") => ConfigCommand::Print,
+            Some("reset") => ConfigCommand::Reset,
+            _ => ConfigCommand::Path,
+        }),
+        Some(("status", _)) => CliCommand::Status,
+        Some(("show", _)) => CliCommand::Control("show_window"),
+        Some(("hide", _)) => CliCommand::Control("hide_window"),
+        Some(("reload", _)) => CliCommand::Control("reload_config"),
+        Some(("quit", _)) => CliCommand::Control("quit"),
+        Some(("export", sub)) => CliCommand::Export {
+            output: sub.value_of("output").map(|x| x.to_owned()),
+            frames: sub.is_present("frames"),
+        },
+        Some(("schema", _)) => CliCommand::Schema,
+        _ => CliCommand::Run {
+            replay: matches.value_of("replay").map(absolute),
+            headless: headless(&matches),
+        },
+    }
+}
+
+/// File arguments can go to an instance started somewhere else, relative paths wouldn't resolve there
+fn absolute(path: &str) -> String {
+    match std::env::current_dir() {
+        Ok(dir) => dir.join(path).to_string_lossy().into_owned(),
+        Err(_) => path.to_owned(),
+    }
+}
+
+/// Runs a command that doesn't start the client, returns once it's done
+pub async fn run_oneshot(cmd: CliCommand) -> anyhow::Result<()> {
+    match cmd {
+        CliCommand::Run { .. } => Ok(()),
+        CliCommand::Upload(path) => upload(path).await,
+        CliCommand::Config(cmd) => config(cmd),
+        CliCommand::Status => status().await,
+        CliCommand::Control(method) => control(method).await,
+        CliCommand::Export { output, frames } => export(output, frames),
+        CliCommand::Schema => {
+            println!("{}", serde_json::to_string_pretty(&crate::ws_protocol::schema())?);
+            Ok(())
+        },
+    }
+}
+
+async fn upload(path: String) -> anyhow::Result<()> {
+    if !crate::threads::control_port_is_available() {
+        crate::replay_recv::request_current_instance("upload_replay", serde_json::json!({ "path": path })).await?;
+        println!("Upload started in the running instance");
+        return Ok(());
+    }
+
+    let replay = Arc::new(crate::client::get_replay_file_content(path)?);
+    let md5 = crate::replay_upload::replay_md5(&replay);
+    match crate::replay_upload::upload(replay, true).await {
+        Ok(()) | Err(UploadError::Exists) => {
+            println!("{}", crate::replay_upload::replay_link(&md5));
+            Ok(())
+        },
+        Err(UploadError::Network) => anyhow::bail!("Couldn't reach the replay server"),
+        Err(UploadError::Rejected) => anyhow::bail!("Replay rejected by the server"),
+    }
+}
+
+fn config(cmd: ConfigCommand) -> anyhow::Result<()> {
+    let path = crate::config::get_priority_file();
+    match cmd {
+        ConfigCommand::Validate => {
+            let problems = crate::config::validate_file(&path)?;
+            if !problems.is_empty() {
+                for problem in &problems {
+                    println!("{problem}");
+                }
+                anyhow::bail!("{:?} has {} problem(s)", path, problems.len());
+            }
+            println!("{:?} is valid", path);
+        },
+        ConfigCommand::Print => println!("{}", crate::config::serialize_config((*crate::config::CONFIG.load_full()).clone())?),
+        ConfigCommand::Path => println!("{}", path.display()),
+        ConfigCommand::Reset => println!("Reset {:?}", crate::config::reset_to_default()?),
+    }
+    Ok(())
+}
+
+async fn status() -> anyhow::Result<()> {
+    if crate::threads::control_port_is_available() {
+        anyhow::bail!("ddstats-rust is not running");
+    }
+
+    let reply = crate::replay_recv::request_current_instance("status", serde_json::Value::Null).await?;
+    let status: StatusReply = serde_json::from_value(reply)?;
+    println!("version:      {}", status.version);
+    println!("connection:   {:?}", status.connection);
+    println!("player:       {}", status.player_name);
+    println!("game status:  {}", status.game_status);
+    println!("time:         {:.4}", status.time);
+    println!("snowflake:    {}", status.snowflake);
+    println!("submit queue: {}", status.submit_queue);
+    Ok(())
+}
+
+async fn control(method: &str) -> anyhow::Result<()> {
+    if crate::threads::control_port_is_available() {
+        anyhow::bail!("ddstats-rust is not running");
+    }
+
+    crate::replay_recv::request_current_instance(method, serde_json::Value::Null).await?;
+    println!("OK");
+    Ok(())
+}
+
+#[derive(serde::Serialize)]
+struct ExportedRun {
+    #[serde(flatten)]
+    summary: RunSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frames: Option<Vec<StatsFrame>>,
+}
+
+fn export(output: Option<String>, frames: bool) -> anyhow::Result<()> {
+    let runs: Vec<ExportedRun> = crate::run_history::load_all_runs()
+        .iter()
+        .map(|run| ExportedRun {
+            summary: RunSummary::from_stored(run),
+            frames: if frames { Some(run.game_frames()) } else { None },
+        })
+        .collect();
+
+    let json = serde_json::to_string_pretty(&runs)?;
+    match output {
+        Some(path) => {
+            std::fs::write(&path, json)?;
+            eprintln!("Exported {} runs to {}", runs.len(), path);
+        },
+        None => println!("{json}"),
+    }
+    Ok(())
+}
@@ -5,14 +5,13 @@
 use crate::consts::*;
 use crate::threads::{State, AAS, Message};
 use chashmap::CHashMap;
-use clipboard::{ClipboardProvider, ClipboardContext};
 use ddcore_rs::ddinfo;
 use ddcore_rs::ddinfo::ddcl_submit::DdclSecrets;
 use ddcore_rs::memory::{ConnectionParams, GameConnection, MemoryOverride, OperatingSystem};
 use ddcore_rs::models::{GameStatus, StatsBlockWithFrames, StatsFrame};
 use lazy_static::lazy_static;
 use num_traits::FromPrimitive;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::OnceCell;
 use std::fs::File;
 use std::sync::Arc;
@@ -25,7 +24,7 @@ lazy_static! {
     static ref CL_EXISTS_CACHE: CHashMap<String, bool> = CHashMap::new();
 }
 
-#[derive(PartialEq, Debug, Clone, Serialize)]
+#[derive(PartialEq, Debug, Clone, Serialize, Deserialize, schemars::JsonSchema)]
 pub enum ConnectionState {
     NotConnected,
     Connecting,
@@ -42,6 +41,7 @@ pub struct GamePollClient {
     pub connection: GameConnection,
     pub connection_state: ConnectionState,
     pub submitted_data: bool,
+    pub archived_replay: bool,
     pub state: AAS<State>,
     pub connecting_start: Instant,
     pub last_connection_attempt: Instant,
@@ -59,6 +59,7 @@ impl GamePollClient {
                 state,
                 connection: GameConnection::dead_connection(),
                 submitted_data: false,
+                archived_replay: false,
                 connecting_start: Instant::now(),
                 connection_state: ConnectionState::NotConnected,
                 last_game_state: GameStatus::Menu,
@@ -90,29 +91,6 @@ impl GamePollClient {
                         Ok(Message::UploadReplayBuffer) => {
                             c.upload_replay_flag = true;
                         },
-                        Ok(Message::UploadReplayData(data, manual)) => {
-                            let snd_msg = c.state.load().msg_bus.0.clone();
-                            let _ = snd_msg.send(Message::Log("Uploading Replay...".to_string()));
-                            let dclone = data.clone(); // Refclone
-                            tokio::spawn(async move {
-                                match ddcore_rs::ddreplay::upload_replay(data, manual).await {
-                                    Ok(_) => {
-                                        let _ = snd_msg.send(Message::Log("Replay Uploaded".to_string()));
-                                        if crate::config::cfg().auto_clipboard && manual {
-                                            log::info!("Setting manual clipboard");
-                                            let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-                                            let replay_hash = format!("{:?}", ddcore_rs::md5::compute(&dclone[..]));
-                                            let new_clip = format!("https://ddreplay.herokuapp.com/replay/{}", replay_hash);
-                                            ctx.set_contents(new_clip).unwrap();
-                                        }
-                                    },
-                                    Err(e) => {
-                                        let _ = snd_msg.send(Message::Log("Replay Exists".to_string()));
-                                        log::info!("Failed replay upload: {:?}", e);
-                                    }
-                                }
-                            });
-                        },
                         _ => {},
                     },
                     _elapsed = interval.tick() => {
@@ -238,6 +216,7 @@ impl GamePollClient {
 
             if GamePollClient::new_run_started(&status, &old) {
                 self.submitted_data = false;
+                self.archived_replay = false;
             }
 
             let data = Arc::new(data);
@@ -245,38 +224,28 @@ impl GamePollClient {
 
             if self.upload_replay_flag {
                 if let Ok(replay) = self.connection.replay_bin() {
-                    let msg_bus = state.msg_bus.0.clone();
-                    tokio::spawn(async move {
-                        let r = Arc::new(replay);
-                        let rclone = r.clone();
-                        match ddcore_rs::ddreplay::upload_replay(r, true).await {
-                            Ok(_) => {
-                                if crate::config::cfg().auto_clipboard {
-                                    let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-                                    let replay_hash = format!("{:?}", ddcore_rs::md5::compute(&rclone[..]));
-                                    let new_clip = format!("https://ddstats.live/replay/{}", replay_hash);
-                                    ctx.set_contents(new_clip).unwrap();
-                                }
-                                let _ = msg_bus.send(Message::Log("Replay Uploaded".to_string()));
-                            },
-                            Err(e) => {
-                                if crate::config::cfg().auto_clipboard {
-                                    let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-                                    let replay_hash = format!("{:?}", ddcore_rs::md5::compute(&rclone[..]));
-                                    let new_clip = format!("https://ddstats.live/replay/{}", replay_hash);
-                                    ctx.set_contents(new_clip).unwrap();
-                                }
-                                let _ = msg_bus.send(Message::Log("Replay Exists".to_string()));
-                                log::info!("Failed replay upload: {e:?}");
-                            }
-                        }
-                    });
+                    let _ = state.msg_bus.0.send(Message::UploadReplayData(Arc::new(replay), true));
                 } else {
                     let _ = state.msg_bus.0.send(Message::Log("Replay Rejected".to_string()));
                 }
                 self.upload_replay_flag = false;
             }
 
+            if self.should_archive(&data, &status) {
+                // Only marked once the replay was read, a failed read is tried again next poll
+                if let Ok(replay) = self.connection.replay_bin() {
+                    self.archived_replay = true;
+                    let log_sender = state.msg_bus.0.clone();
+                    tokio::task::spawn_blocking(move || match crate::replay_library::auto_save(&replay) {
+                        Ok(path) => log::info!("Auto saved replay: {path:?}"),
+                        Err(e) => {
+                            log::error!("Replay auto save failed: {e:?}");
+                            let _ = log_sender.send(Message::Log("Replay Save Fail".to_string()));
+                        },
+                    });
+                }
+            }
+
             if self.should_submit(&data, &status) {
                 log::info!("Attempting to submit run");
                 if let Ok(replay) = self.connection.replay_bin() {
@@ -382,6 +351,7 @@ impl GamePollClient {
             enemies_alive: last.enemies_alive,
             level_gems: last.level_gems,
             homing_daggers: last.homing,
+            starting_time: data.block.starting_time,
             stats: data.frames.clone(),
         }, snowflake, replay.clone())
     }
@@ -398,6 +368,14 @@ impl GamePollClient {
         || status == GameStatus::LocalReplay)
     }
 
+    #[rustfmt::skip]
+    fn should_archive(&self, data: &StatsBlockWithFrames, status: &GameStatus) -> bool {
+        crate::config::cfg().replay_library.auto_save.enabled
+        && data.block.stats_finished_loading
+        && !self.archived_replay
+        && *status == GameStatus::Dead
+    }
+
     #[rustfmt::skip]
     fn new_run_started(status: &GameStatus, old: &GameStatus) -> bool {
         let status = *status;
@@ -422,7 +400,7 @@ impl GamePollClient {
     }
 }
 
-fn get_replay_file_content(path: String) -> anyhow::Result<Vec<u8>> {
+pub fn get_replay_file_content(path: String) -> anyhow::Result<Vec<u8>> {
     let mut f = File::open(path)?;
     ddcore_rs::models::replay::DdRpl::validate_reader_output_bin(&mut f)
 }
@@ -486,6 +464,7 @@ pub struct CompiledRun {
     pub daggers_fired: i32,
     pub per_enemy_alive_count: [i16; 17],
     pub per_enemy_kill_count: [i16; 17],
+    pub starting_time: f32,
     pub stats: Vec<StatsFrame>,
 }
 
@@ -4,6 +4,7 @@
 
 use std::fs::File;
 use std::io::BufReader;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::path::Path;
 use std::path::PathBuf;
 use std::sync::Arc;
@@ -16,17 +17,21 @@ use ron::ser::PrettyConfig;
 use serde::Deserialize;
 use tui::style::Style;
 use crate::threads::AAS;
-use crate::ui::modules::GameDataModules;
+use crate::ui::modules::{GameDataModules, Split};
 
 const DEFAULT_CFG: &str = include_str!("../default_cfg.ron");
 type VersionedCfg = <DDStatsRustConfig as obake::Versioned>::Versioned;
 
 lazy_static! {
     pub static ref CONFIG: AAS<DDStatsRustConfig> = Arc::new(ArcSwap::from_pointee(get_config()));
+    pub static ref LOAD_REPORT: ArcSwap<ConfigLoadReport> = ArcSwap::from_pointee(ConfigLoadReport::default());
+    /// File the config in use came from, None when it's a fallback
+    static ref LOADED_FILE: ArcSwap<Option<PathBuf>> = ArcSwap::from_pointee(None);
 }
 
 #[obake::versioned]
 #[obake(version("1.0.0"))]
+#[obake(version("1.1.0"))]
 #[obake(derive(serde::Serialize, serde::Deserialize))]
 #[derive(Deserialize, serde::Serialize, Clone)]
 pub struct DDStatsRustConfig {
@@ -56,6 +61,20 @@ pub struct DDStatsRustConfig {
     pub process_name_override: Option<String>,
     #[obake(cfg(">=1.0.0"))]
     pub open_game_on_replay_request: bool,
+    #[obake(cfg(">=1.1.0"))]
+    pub headless: bool,
+    #[obake(cfg(">=1.1.0"))]
+    pub watch_config: bool,
+    #[obake(cfg(">=1.1.0"))]
+    pub save_run_history: bool,
+    #[obake(cfg(">=1.1.0"))]
+    pub livesplit: LiveSplit,
+    #[obake(cfg(">=1.1.0"))]
+    pub local_server: LocalServer,
+    #[obake(cfg(">=1.1.0"))]
+    pub replay_library: ReplayLibrary,
+    #[obake(cfg(">=1.1.0"))]
+    pub replay_store: ReplayStoreConfig,
 
     #[obake(cfg(">=1.0.0"))]
     #[obake(inherit)]
@@ -73,6 +92,7 @@ pub struct DDStatsRustConfig {
 
 #[obake::versioned]
 #[obake(version("1.0.0"))]
+#[obake(version("1.1.0"))]
 #[obake(derive(serde::Serialize, serde::Deserialize))]
 #[derive(Deserialize, serde::Serialize, Clone)]
 pub struct UiConf {
@@ -96,6 +116,10 @@ pub struct UiConf {
     pub current_split_live_change: bool,
     #[obake(cfg(">=1.0.0"))]
     pub always_show_splits: bool,
+    #[obake(cfg(">=1.1.0"))]
+    pub active_split_profile: String,
+    #[obake(cfg(">=1.1.0"))]
+    pub split_profiles: Vec<SplitProfile>,
 
     // keeping this down here to hope that the deserializer leaves the junk in the bottom
     #[obake(cfg(">=1.0.0"))]
@@ -105,6 +129,212 @@ pub struct UiConf {
     pub theming: Theming,
 }
 
+#[derive(Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct LocalServer {
+    pub address: String,
+    pub port: u16,
+    pub control_address: String,
+    pub control_port: u16,
+    pub allow_lan: bool,
+    pub token: String,
+    pub allowed_origins: Vec<String>,
+    pub open_read_only: bool,
+    pub tls: LocalTls,
+}
+
+/// Second listener for wss:// and https://, empty paths use a self-signed pair from the config dir
+#[derive(Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct LocalTls {
+    pub enabled: bool,
+    pub port: u16,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+impl Default for LocalTls {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            port: 13667,
+            cert_path: String::new(),
+            key_path: String::new(),
+        }
+    }
+}
+
+impl Default for LocalServer {
+    fn default() -> Self {
+        Self {
+            address: "127.0.0.1".to_string(),
+            port: 13666,
+            control_address: "127.0.0.1".to_string(),
+            control_port: 18639,
+            allow_lan: false,
+            token: String::new(),
+            allowed_origins: vec![
+                "https://ddstats.live".to_string(),
+                "http://localhost:13666".to_string(),
+                "http://127.0.0.1:13666".to_string(),
+            ],
+            open_read_only: true,
+            tls: LocalTls::default(),
+        }
+    }
+}
+
+#[derive(Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct ReplayLibrary {
+    /// Empty means <config dir>/replays
+    pub dir: String,
+    pub save_history_replays: bool,
+    pub auto_save: AutoSave,
+}
+
+impl Default for ReplayLibrary {
+    fn default() -> Self {
+        Self {
+            dir: String::new(),
+            save_history_replays: true,
+            auto_save: AutoSave::default(),
+        }
+    }
+}
+
+impl ReplayLibrary {
+    pub fn dir(&self) -> PathBuf {
+        if self.dir.is_empty() {
+            get_config_dir().join("replays")
+        } else {
+            PathBuf::from(&self.dir)
+        }
+    }
+
+    pub fn auto_save_dir(&self) -> PathBuf {
+        if self.auto_save.dir.is_empty() {
+            self.dir().join("auto")
+        } else {
+            PathBuf::from(&self.auto_save.dir)
+        }
+    }
+}
+
+/// Where uploaded replays go and what links to them look like
+#[derive(Deserialize, serde::Serialize, Clone, PartialEq, Debug)]
+pub enum ReplayStoreConfig {
+    /// The ddreplay service, `link_base` + md5 is the shared link
+    DdReplay { link_base: String },
+    /// Files kept in `dir` (empty for <config dir>/hosted) and served by the local server at /hosted/,
+    /// `public_url` is how others reach it (empty for the local server address)
+    LocalHttp { dir: String, public_url: String },
+    /// Files copied to `dir` (a shared or synced folder), links are `link_base` + file name, or the path if empty
+    Directory { dir: String, link_base: String },
+}
+
+impl Default for ReplayStoreConfig {
+    fn default() -> Self {
+        ReplayStoreConfig::DdReplay { link_base: "https://ddstats.live/replay/".to_string() }
+    }
+}
+
+/// Replay of every death written to disk, retention only ever deletes from auto_save_dir
+#[derive(Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct AutoSave {
+    pub enabled: bool,
+    /// Empty means <library dir>/auto
+    pub dir: String,
+    /// {player} {player_id} {time} {spawnset} {date} {timestamp}
+    pub file_template: String,
+    /// Best N runs of every spawnset are kept, 0 turns the rule off
+    pub keep_top_per_spawnset: usize,
+    /// Runs at least this long are kept, 0 turns the rule off
+    pub keep_above_seconds: f32,
+}
+
+impl Default for AutoSave {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            dir: String::new(),
+            file_template: "{player}_{time}_{spawnset}_{date}".to_string(),
+            keep_top_per_spawnset: 0,
+            keep_above_seconds: 0.,
+        }
+    }
+}
+
+impl LocalServer {
+    /// Websocket, overlays and http api
+    pub fn server_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_ip(&self.address), self.port)
+    }
+
+    /// Same interface as server_addr, on the tls port
+    pub fn tls_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_ip(&self.address), self.tls.port)
+    }
+
+    /// Replay receiver and control channel
+    pub fn control_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind_ip(&self.control_address), self.control_port)
+    }
+
+    /// Where a second instance or the cli reaches the control channel
+    pub fn control_connect_addr(&self) -> SocketAddr {
+        let addr = self.control_addr();
+        if addr.ip().is_unspecified() {
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), addr.port())
+        } else {
+            addr
+        }
+    }
+
+    /// Anything but loopback needs allow_lan, otherwise we stay on 127.0.0.1
+    fn bind_ip(&self, address: &str) -> IpAddr {
+        match address.parse::<IpAddr>() {
+            Ok(ip) if ip.is_loopback() || self.allow_lan => ip,
+            _ => IpAddr::V4(Ipv4Addr::LOCALHOST),
+        }
+    }
+}
+
+#[derive(Deserialize, serde::Serialize, Clone)]
+#[serde(default)]
+pub struct LiveSplit {
+    pub enabled: bool,
+    pub address: String,
+    pub split_on: Vec<LiveSplitEvent>,
+}
+
+impl Default for LiveSplit {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            address: "127.0.0.1:16834".to_string(),
+            split_on: vec![LiveSplitEvent::Level2, LiveSplitEvent::Level3, LiveSplitEvent::Level4, LiveSplitEvent::LeviDown, LiveSplitEvent::Death],
+        }
+    }
+}
+
+#[derive(Deserialize, PartialEq, serde::Serialize, Clone, Copy, Debug)]
+pub enum LiveSplitEvent {
+    Level2,
+    Level3,
+    Level4,
+    LeviDown,
+    OrbDown,
+    Death,
+}
+
+#[derive(Deserialize, serde::Serialize, Clone)]
+pub struct SplitProfile {
+    pub name: String,
+    pub splits: Vec<Split>,
+}
+
 #[derive(Deserialize, PartialEq, serde::Serialize, Clone)]
 pub enum LogoStyle {
     Auto,
@@ -115,6 +345,7 @@ pub enum LogoStyle {
 
 #[obake::versioned]
 #[obake(version("1.0.0"))]
+#[obake(version("1.1.0"))]
 #[obake(derive(serde::Serialize, serde::Deserialize))]
 #[derive(Deserialize, Clone, serde::Serialize)]
 pub struct Theming {
@@ -124,6 +355,7 @@ pub struct Theming {
 
 #[obake::versioned]
 #[obake(version("1.0.0"))]
+#[obake(version("1.1.0"))]
 #[obake(derive(serde::Serialize, serde::Deserialize))]
 #[derive(Deserialize, Clone, serde::Serialize)]
 pub struct Styles {
@@ -159,6 +391,7 @@ pub struct Styles {
 
 #[obake::versioned]
 #[obake(version("1.0.0"))]
+#[obake(version("1.1.0"))]
 #[obake(derive(serde::Serialize, serde::Deserialize))]
 #[derive(Deserialize, serde::Serialize, Clone)]
 pub struct Stream {
@@ -172,6 +405,7 @@ pub struct Stream {
 
 #[obake::versioned]
 #[obake(version("1.0.0"))]
+#[obake(version("1.1.0"))]
 #[obake(derive(serde::Serialize, serde::Deserialize))]
 #[derive(Deserialize, serde::Serialize, Clone)]
 pub struct Submit {
@@ -187,6 +421,7 @@ pub struct Submit {
 
 #[obake::versioned]
 #[obake(version("1.0.0"))]
+#[obake(version("1.1.0"))]
 #[obake(derive(serde::Serialize, serde::Deserialize))]
 #[derive(Deserialize, serde::Serialize, Clone)]
 pub struct Discord {
@@ -198,8 +433,154 @@ pub struct Discord {
     pub notify_custom_spawnsets: bool,
 }
 
+//
+// Migrations, one From per version bump for every versioned struct
+//
+
+impl From<DDStatsRustConfig!["1.0.0"]> for DDStatsRustConfig!["1.1.0"] {
+    fn from(old: DDStatsRustConfig!["1.0.0"]) -> Self {
+        Self {
+            host: old.host,
+            grpc_host: old.grpc_host,
+            offline: old.offline,
+            debug_logs: old.debug_logs,
+            auto_clipboard: old.auto_clipboard,
+            linux_restart_as_child: old.linux_restart_as_child,
+            use_linux_proton: old.use_linux_proton,
+            tray_icon: old.tray_icon,
+            hide_window_on_start: old.hide_window_on_start,
+            upload_replays_automatically: old.upload_replays_automatically,
+            block_marker_override: old.block_marker_override,
+            process_name_override: old.process_name_override,
+            open_game_on_replay_request: old.open_game_on_replay_request,
+            headless: false,
+            watch_config: true,
+            save_run_history: true,
+            livesplit: LiveSplit::default(),
+            local_server: LocalServer::default(),
+            replay_library: ReplayLibrary::default(),
+            replay_store: ReplayStoreConfig::default(),
+            stream: old.stream.into(),
+            submit: old.submit.into(),
+            discord: old.discord.into(),
+            ui_conf: old.ui_conf.into(),
+        }
+    }
+}
+
+impl From<UiConf!["1.0.0"]> for UiConf!["1.1.0"] {
+    fn from(old: UiConf!["1.0.0"]) -> Self {
+        // Every inline HomingSplits becomes a profile ("Default", "Default 2", ...). The first
+        // module follows the active profile from now on, the others stay inline so the layout doesn't change
+        let mut split_profiles: Vec<SplitProfile> = vec![];
+        let game_data_modules = old.game_data_modules.into_iter().map(|module| match module {
+            GameDataModules::HomingSplits(splits) => {
+                let first = split_profiles.is_empty();
+                let name = if first { "Default".to_string() } else { format!("Default {}", split_profiles.len() + 1) };
+                split_profiles.push(SplitProfile { name, splits: splits.clone() });
+                if first { GameDataModules::ProfileSplits } else { GameDataModules::HomingSplits(splits) }
+            },
+            other => other,
+        }).collect();
+
+        Self {
+            enabled: old.enabled,
+            logo_style: old.logo_style,
+            hide_logs: old.hide_logs,
+            logo: old.logo,
+            orb_connection_animation: old.orb_connection_animation,
+            column_distance: old.column_distance,
+            show_help_on_border: old.show_help_on_border,
+            current_split_marker: old.current_split_marker,
+            current_split_live_change: old.current_split_live_change,
+            always_show_splits: old.always_show_splits,
+            active_split_profile: "Default".to_string(),
+            split_profiles,
+            game_data_modules,
+            theming: old.theming.into(),
+        }
+    }
+}
+
+impl From<Theming!["1.0.0"]> for Theming!["1.1.0"] {
+    fn from(old: Theming!["1.0.0"]) -> Self {
+        Self { styles: old.styles.into() }
+    }
+}
+
+impl From<Styles!["1.0.0"]> for Styles!["1.1.0"] {
+    fn from(old: Styles!["1.0.0"]) -> Self {
+        Self {
+            text: old.text,
+            logo: old.logo,
+            logs: old.logs,
+            logs_title: old.logs_title,
+            log_text: old.log_text,
+            most_recent_log: old.most_recent_log,
+            game_data: old.game_data,
+            game_data_title: old.game_data_title,
+            split_name: old.split_name,
+            accent: old.accent,
+            split_diff_pos: old.split_diff_pos,
+            split_diff_neg: old.split_diff_neg,
+            split_diff_neutral: old.split_diff_neutral,
+            split_diff_gold: old.split_diff_gold,
+        }
+    }
+}
+
+impl From<Stream!["1.0.0"]> for Stream!["1.1.0"] {
+    fn from(old: Stream!["1.0.0"]) -> Self {
+        Self {
+            stats: old.stats,
+            replay_stats: old.replay_stats,
+            non_default_spawnsets: old.non_default_spawnsets,
+        }
+    }
+}
+
+impl From<Submit!["1.0.0"]> for Submit!["1.1.0"] {
+    fn from(old: Submit!["1.0.0"]) -> Self {
+        Self {
+            stats: old.stats,
+            replay_stats: old.replay_stats,
+            non_default_spawnsets: old.non_default_spawnsets,
+            ddcl: old.ddcl,
+        }
+    }
+}
+
+impl From<Discord!["1.0.0"]> for Discord!["1.1.0"] {
+    fn from(old: Discord!["1.0.0"]) -> Self {
+        Self {
+            notify_above_1000: old.notify_above_1000,
+            notify_player_best: old.notify_player_best,
+            notify_custom_spawnsets: old.notify_custom_spawnsets,
+        }
+    }
+}
+
+/// Version name of a parsed file, None if it's already the newest layout
+fn outdated_version(conf: &VersionedCfg) -> Option<&'static str> {
+    match conf {
+        VersionedCfg::DDStatsRustConfig_v1_0_0(_) => Some("1.0.0"),
+        _ => None,
+    }
+}
+
+/// Rewrites an outdated config file in the newest layout, the original is copied next to it first
+fn upgrade_file(path: &Path, from_version: &str, conf: &DDStatsRustConfig) -> anyhow::Result<PathBuf> {
+    let backup_path = path.with_file_name(format!("config.v{}.backup", from_version.replace('.', "_")));
+    std::fs::copy(path, &backup_path)?;
+
+    let serialized = serialize_config(conf.clone())?;
+    let mut f = File::create(path)?;
+    std::io::copy(&mut BufReader::new(serialized.as_bytes()), &mut f)?;
+    Ok(backup_path)
+}
+
 #[cfg(target_os = "linux")]
-fn get_priority_file() -> PathBuf {
+pub fn get_priority_file() -> PathBuf {
     let exe_path = std::env::current_exe().unwrap();
     let config_path = exe_path.with_file_name("config.ron");
     if config_path.exists() {
@@ -217,15 +598,37 @@ fn get_priority_file() -> PathBuf {
 }
 
 #[cfg(target_os = "windows")]
-fn get_priority_file() -> PathBuf {
+pub fn get_priority_file() -> PathBuf {
     let exe_path = std::env::current_exe().unwrap();
     exe_path.with_file_name("config.ron")
 }
 
+/// What happened while picking a config file, shown in the logs on startup
+#[derive(Clone, Default)]
+pub struct ConfigLoadReport {
+    pub loaded_from: String,
+    pub rejected: Vec<(String, String)>,
+    pub warnings: Vec<String>,
+}
+
+impl ConfigLoadReport {
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("Config: {}", self.loaded_from)];
+        lines.extend(self.rejected.iter().map(|(file, why)| format!("Rejected {file}: {why}")));
+        lines.extend(self.warnings.iter().map(|w| format!("Config Warning: {w}")));
+        lines
+    }
+}
+
+fn parse_file(path: &Path) -> anyhow::Result<VersionedCfg> {
+    let f = File::open(path)?;
+    // ron errors carry the line:column of the problem
+    from_reader(f).map_err(|e| anyhow::anyhow!("{}", e))
+}
+
 fn try_priority_file() -> anyhow::Result<VersionedCfg> {
     if get_priority_file().exists() {
-        let f = File::open(&get_priority_file())?;
-        return Ok(from_reader(f)?);
+        return parse_file(&get_priority_file());
     }
     
     anyhow::bail!("Priority config file not found");
@@ -237,21 +640,173 @@ fn try_dbg_file() -> anyhow::Result<VersionedCfg> {
         let c = format!("{}/default_cfg.ron", dir);
         let fp = Path::new(c.as_str());
         if fp.exists() {
-            let f = File::open(&fp)?;
-            return Ok(from_reader(f)?);
+            return parse_file(fp);
         }
     }
     
     anyhow::bail!("Debug config not found");
 }
 
-pub fn try_save_with_backup() -> anyhow::Result<()> {
-    let current_cfg = (*CONFIG.load_full()).clone();
-    let current_cfg: VersionedCfg = current_cfg.into();
-    let serialized = ron::ser::to_string_pretty(
-        &current_cfg, 
+/// Semantic checks for things that parse fine but can't be right
+pub fn check(cfg: &DDStatsRustConfig) -> Vec<String> {
+    let mut problems = vec![];
+
+    if cfg.ui_conf.column_distance > 100 {
+        problems.push(format!("ui_conf.column_distance is a percentage, got {}", cfg.ui_conf.column_distance));
+    }
+
+    for (name, url) in [("host", &cfg.host), ("grpc_host", &cfg.grpc_host)] {
+        match url.parse::<hyper::Uri>() {
+            Ok(uri) if uri.scheme().is_some() && uri.host().is_some() => {},
+            _ => problems.push(format!("{name} is not a valid http(s) url: {url:?}")),
+        }
+    }
+
+    for (name, address) in [("local_server.address", &cfg.local_server.address), ("local_server.control_address", &cfg.local_server.control_address)] {
+        match address.parse::<IpAddr>() {
+            Ok(ip) if !ip.is_loopback() && !cfg.local_server.allow_lan => {
+                problems.push(format!("{name} {address} is not loopback and allow_lan is off, using 127.0.0.1"));
+            },
+            Ok(_) => {},
+            Err(_) => problems.push(format!("{name} is not an ip address, got {address:?}")),
+        }
+    }
+
+    if cfg.local_server.port == cfg.local_server.control_port {
+        problems.push(format!("local_server.port and local_server.control_port are both {}", cfg.local_server.port));
+    }
+
+    let auto_save = &cfg.replay_library.auto_save;
+    if auto_save.enabled && auto_save.file_template.trim().is_empty() {
+        problems.push("replay_library.auto_save.file_template is empty".to_string());
+    }
+    if auto_save.file_template.contains(['/', '\\']) {
+        problems.push("replay_library.auto_save.file_template can't contain folders, use replay_library.auto_save.dir".to_string());
+    }
+    if auto_save.keep_above_seconds < 0. {
+        problems.push(format!("replay_library.auto_save.keep_above_seconds is negative: {}", auto_save.keep_above_seconds));
+    }
+
+    match &cfg.replay_store {
+        ReplayStoreConfig::DdReplay { link_base } if link_base.parse::<hyper::Uri>().map_or(true, |u| u.scheme().is_none()) => {
+            problems.push(format!("replay_store link_base is not a url: {link_base:?}"));
+        },
+        ReplayStoreConfig::Directory { dir, .. } if dir.is_empty() => {
+            problems.push("replay_store Directory needs a dir".to_string());
+        },
+        _ => {},
+    }
+
+    let tls = &cfg.local_server.tls;
+    if tls.enabled {
+        if tls.port == cfg.local_server.port || tls.port == cfg.local_server.control_port {
+            problems.push(format!("local_server.tls.port {} is already used by the local server", tls.port));
+        }
+        if tls.cert_path.is_empty() != tls.key_path.is_empty() {
+            problems.push("local_server.tls needs both cert_path and key_path, or neither to use a generated certificate".to_string());
+        }
+        for path in [&tls.cert_path, &tls.key_path] {
+            if !path.is_empty() && !Path::new(path).is_file() {
+                problems.push(format!("local_server.tls file not found: {path}"));
+            }
+        }
+    }
+
+    if cfg.livesplit.enabled && cfg.livesplit.address.parse::<SocketAddr>().is_err() {
+        problems.push(format!("livesplit.address is not an ip:port, got {:?}", cfg.livesplit.address));
+    }
+
+    for module in &cfg.ui_conf.game_data_modules {
+        if let GameDataModules::HomingSplits(splits) = module {
+            check_splits(splits, &mut problems);
+        }
+    }
+
+    for profile in &cfg.ui_conf.split_profiles {
+        if profile.name.trim().is_empty() {
+            problems.push("a split profile has an empty name".to_string());
+        }
+        check_splits(&profile.splits, &mut problems);
+    }
+
+    problems
+}
+
+fn check_splits(splits: &[Split], problems: &mut Vec<String>) {
+    let mut last_time = f32::MIN;
+    for (i, (name, time, ..)) in splits.iter().enumerate() {
+        if name.trim().is_empty() {
+            problems.push(format!("split #{} has an empty name", i + 1));
+        }
+        if *time <= last_time {
+            problems.push(format!("split {name:?} ({time}) is not after the previous split ({last_time})"));
+        }
+        last_time = *time;
+    }
+}
+
+/// Active split profile, falls back to the first one if the name doesn't match any
+pub fn active_split_profile(cfg: &DDStatsRustConfig) -> Option<&SplitProfile> {
+    let profiles = &cfg.ui_conf.split_profiles;
+    profiles.iter()
+        .find(|p| p.name.eq(&cfg.ui_conf.active_split_profile))
+        .or_else(|| profiles.first())
+}
+
+/// Selects a split profile by name, or the next one if no name is given. Returns the new active name
+pub fn select_split_profile(name: Option<&str>) -> anyhow::Result<String> {
+    let mut c = (*CONFIG.load_full()).clone();
+    let profiles = &c.ui_conf.split_profiles;
+
+    if profiles.is_empty() {
+        anyhow::bail!("No split profiles configured");
+    }
+
+    let idx = match name {
+        Some(name) => profiles.iter().position(|p| p.name.eq(name)).ok_or_else(|| anyhow::anyhow!("No split profile named {name:?}"))?,
+        None => {
+            let current = profiles.iter().position(|p| p.name.eq(&c.ui_conf.active_split_profile)).unwrap_or(0);
+            (current + 1) % profiles.len()
+        }
+    };
+
+    c.ui_conf.active_split_profile = profiles[idx].name.clone();
+    let active = c.ui_conf.active_split_profile.clone();
+    CONFIG.swap(Arc::new(c));
+    Ok(active)
+}
+
+pub fn serialize_config(cfg: DDStatsRustConfig) -> anyhow::Result<String> {
+    let versioned: VersionedCfg = cfg.into();
+    Ok(ron::ser::to_string_pretty(
+        &versioned, 
         PrettyConfig::new().indentor("    ".to_string()).depth_limit(4).decimal_floats(true)
-    )?;
+    )?)
+}
+
+/// Parses a config file and runs the semantic checks on it, returns the problems found
+pub fn validate_file(path: &Path) -> anyhow::Result<Vec<String>> {
+    let conf: DDStatsRustConfig = parse_file(path)?.into();
+    Ok(check(&conf))
+}
+
+/// Replaces the priority config with the bundled default, the old file is kept as config.backup
+pub fn reset_to_default() -> anyhow::Result<PathBuf> {
+    let best_file = get_priority_file();
+
+    if best_file.exists() {
+        std::fs::copy(&best_file, best_file.with_file_name("config.backup"))?;
+    } else if let Some(dir) = best_file.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    let mut f_new = File::create(&best_file)?;
+    std::io::copy(&mut BufReader::new(DEFAULT_CFG.as_bytes()), &mut f_new)?;
+    Ok(best_file)
+}
+
+pub fn try_save_with_backup() -> anyhow::Result<()> {
+    let serialized = serialize_config((*CONFIG.load_full()).clone())?;
 
     // Bail if any of the steps fail
     // 1 - Find best config file
@@ -264,6 +819,16 @@ pub fn try_save_with_backup() -> anyhow::Result<()> {
 
     log::info!("Found best file: {best_file:?}");
 
+    // A fallback config must never replace the user's file, even one with a typo in it
+    if LOADED_FILE.load().as_deref() != Some(best_file.as_path()) {
+        log::warn!("Not saving, the config in use didn't come from {best_file:?}");
+        anyhow::bail!("Config in use didn't come from {}, not overwriting it", best_file.display());
+    }
+    if let Err(e) = try_priority_file() {
+        log::warn!("Not saving, {best_file:?} doesn't parse: {e:?}");
+        anyhow::bail!("{} doesn't parse, not overwriting it: {e}", best_file.display());
+    }
+
     // 2 - Create backup
 
     let mut current_config_file = File::open(&best_file)?;
@@ -318,23 +883,65 @@ fn try_create_config_file() -> anyhow::Result<()> {
 }
 
 fn get_config() -> DDStatsRustConfig {
-    if let Ok(conf) = try_priority_file() {
-        return conf.into();
+    let mut report = ConfigLoadReport::default();
+    let conf = load_config(&mut report);
+    report.warnings.extend(check(&conf));
+    LOAD_REPORT.store(Arc::new(report));
+    conf
+}
+
+fn load_config(report: &mut ConfigLoadReport) -> DDStatsRustConfig {
+    match try_priority_file() {
+        Ok(conf) => {
+            let path = get_priority_file();
+            report.loaded_from = format!("{}", path.display());
+            LOADED_FILE.store(Arc::new(Some(path.clone())));
+            let outdated = outdated_version(&conf);
+            let conf: DDStatsRustConfig = conf.into();
+            if let Some(version) = outdated {
+                match upgrade_file(&path, version, &conf) {
+                    Ok(backup) => report.warnings.push(format!("upgraded from v{version}, old file kept at {}", backup.display())),
+                    Err(e) => report.warnings.push(format!("couldn't upgrade from v{version}: {e}")),
+                }
+            }
+            return conf;
+        },
+        Err(e) => report.rejected.push((format!("{}", get_priority_file().display()), format!("{e}"))),
     }
 
-    if let Ok(conf) = try_dbg_file() {
-        return conf.into();
+    match try_dbg_file() {
+        Ok(conf) => {
+            report.loaded_from = "default_cfg.ron (debug)".to_string();
+            return conf.into();
+        },
+        Err(e) => report.rejected.push(("default_cfg.ron (debug)".to_string(), format!("{e}"))),
     }
 
-    if let Err(e) = try_create_config_file() {
-        log::warn!("Failed to create config file: {e:?}");
+    // Only write a fresh file if there's nothing there, never clobber a broken one
+    if !get_priority_file().exists() {
+        match try_create_config_file() {
+            // The new file holds exactly the built-in default, saving over it is fine
+            Ok(()) if get_priority_file().exists() => LOADED_FILE.store(Arc::new(Some(get_priority_file()))),
+            Ok(()) => {},
+            Err(e) => log::warn!("Failed to create config file: {e:?}"),
+        }
     }
 
     // Try to read from config file inside executable as last resort
+    report.loaded_from = "built-in default".to_string();
     let cf: VersionedCfg = from_str(DEFAULT_CFG).unwrap();
     cf.into()
 }
 
+/// Re-reads the priority config file and swaps it in, returns the semantic warnings for it
+pub fn reload() -> anyhow::Result<Vec<String>> {
+    let conf: DDStatsRustConfig = try_priority_file()?.into();
+    let warnings = check(&conf);
+    CONFIG.swap(Arc::new(conf));
+    LOADED_FILE.store(Arc::new(Some(get_priority_file())));
+    Ok(warnings)
+}
+
 pub fn get_log_file_path() -> PathBuf {
     if Path::new("./config.ron").to_owned().exists() {
         Path::new("./debug_logs.txt").to_owned()
@@ -348,6 +955,21 @@ pub fn get_log_file_path() -> PathBuf {
     }
 }
 
+pub fn get_config_dir() -> PathBuf {
+    let priority_file = get_priority_file();
+    match priority_file.parent() {
+        Some(dir) => dir.to_owned(),
+        None => Path::new(".").to_owned(),
+    }
+}
+
+/// Applies a change to the config in use, callers still have to send Message::SaveCfg
+pub fn update(f: impl FnOnce(&mut DDStatsRustConfig)) {
+    let mut c = (*CONFIG.load_full()).clone();
+    f(&mut c);
+    CONFIG.swap(Arc::new(c));
+}
+
 pub fn cfg() -> Guard<Arc<DDStatsRustConfig>> {
     CONFIG.load()
 }
@@ -402,3 +1024,226 @@ pub fn cfg() -> Guard<Arc<DDStatsRustConfig>> {
         */
 
 
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tui::style::{Color, Modifier};
+    use crate::ui::SizeStyle;
+
+    // Every value differs from default_cfg.ron so a field that falls back to a default shows up
+    const V1_0_0: &str = r#"DDStatsRustConfig_v1_0_0((
+    offline: true,
+    debug_logs: false,
+    host: "https://example.invalid",
+    grpc_host: "http://10.0.0.2:80",
+    auto_clipboard: false,
+    linux_restart_as_child: true,
+    use_linux_proton: true,
+    process_name_override: Some("dd.exe"),
+    block_marker_override: Some(123),
+    open_game_on_replay_request: false,
+    upload_replays_automatically: false,
+    tray_icon: false,
+    hide_window_on_start: true,
+    stream: (
+        stats: false,
+        replay_stats: false,
+        non_default_spawnsets: true,
+    ),
+    submit: (
+        stats: false,
+        replay_stats: true,
+        non_default_spawnsets: true,
+        ddcl: false,
+    ),
+    discord: (
+        notify_above_1000: false,
+        notify_player_best: false,
+        notify_custom_spawnsets: true,
+    ),
+    ui_conf: (
+        enabled: false,
+        logo_style: Mini,
+        hide_logs: true,
+        logo: Some("custom"),
+        orb_connection_animation: false,
+        column_distance: 42,
+        show_help_on_border: false,
+        current_split_marker: "<",
+        current_split_live_change: false,
+        always_show_splits: true,
+        theming: (
+            styles: (
+                text: (bg: Some(Rgb(0, 0, 0)), fg: Some(Indexed(100)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                logo: (bg: Some(Rgb(1, 0, 0)), fg: Some(Indexed(101)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                logs: (bg: Some(Rgb(2, 0, 0)), fg: Some(Indexed(102)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                logs_title: (bg: Some(Rgb(3, 0, 0)), fg: Some(Indexed(103)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                log_text: (bg: Some(Rgb(4, 0, 0)), fg: Some(Indexed(104)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                most_recent_log: (bg: Some(Rgb(5, 0, 0)), fg: Some(Indexed(105)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                game_data: (bg: Some(Rgb(6, 0, 0)), fg: Some(Indexed(106)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                game_data_title: (bg: Some(Rgb(7, 0, 0)), fg: Some(Indexed(107)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                split_name: (bg: Some(Rgb(8, 0, 0)), fg: Some(Indexed(108)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                accent: (bg: Some(Rgb(9, 0, 0)), fg: Some(Indexed(109)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                split_diff_pos: (bg: Some(Rgb(10, 0, 0)), fg: Some(Indexed(110)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                split_diff_neg: (bg: Some(Rgb(11, 0, 0)), fg: Some(Indexed(111)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                split_diff_neutral: (bg: Some(Rgb(12, 0, 0)), fg: Some(Indexed(112)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+                split_diff_gold: (bg: Some(Rgb(13, 0, 0)), fg: Some(Indexed(113)), add_modifier: (bits: 1), sub_modifier: (bits: 0)),
+            )
+        ),
+        game_data_modules: [
+            RunData,
+            Homing(Full),
+            HomingSplits([
+                ("LEVI", 366., -105, 0, 5, None),
+                ("700", 709., 2, 3, 4, Some(120)),
+            ]),
+            Gems,
+            HomingSplits([
+                ("A", 100., 1, 2, 3, None),
+            ]),
+            GemsLost(Minimal),
+            HomingSplits([
+                ("B", 200., 0, 0, 5, Some(7)),
+            ]),
+            Spacing,
+        ],
+    ),
+))"#;
+
+    fn style(i: u8) -> Style {
+        Style::default().bg(Color::Rgb(i, 0, 0)).fg(Color::Indexed(i + 100)).add_modifier(Modifier::BOLD)
+    }
+
+    fn split(name: &str, time: f32, offset: i32, positive: i32, neutral: u32, gold: Option<i32>) -> Split {
+        (name.to_string(), time, offset, positive, neutral, gold)
+    }
+
+    fn parse_v1_0_0() -> DDStatsRustConfig {
+        let versioned: VersionedCfg = from_str(V1_0_0).expect("v1.0.0 fixture should parse");
+        assert!(outdated_version(&versioned) == Some("1.0.0"));
+        versioned.into()
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ddstats-rust-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn v1_0_0_fields_survive_migration() {
+        let c = parse_v1_0_0();
+
+        assert!(c.offline);
+        assert!(!c.debug_logs);
+        assert_eq!(c.host, "https://example.invalid");
+        assert_eq!(c.grpc_host, "http://10.0.0.2:80");
+        assert!(!c.auto_clipboard);
+        assert!(c.linux_restart_as_child);
+        assert!(c.use_linux_proton);
+        assert_eq!(c.process_name_override.as_deref(), Some("dd.exe"));
+        assert_eq!(c.block_marker_override, Some(123));
+        assert!(!c.open_game_on_replay_request);
+        assert!(!c.upload_replays_automatically);
+        assert!(!c.tray_icon);
+        assert!(c.hide_window_on_start);
+
+        assert!(!c.stream.stats);
+        assert!(!c.stream.replay_stats);
+        assert!(c.stream.non_default_spawnsets);
+        assert!(!c.submit.stats);
+        assert!(c.submit.replay_stats);
+        assert!(c.submit.non_default_spawnsets);
+        assert!(!c.submit.ddcl);
+        assert!(!c.discord.notify_above_1000);
+        assert!(!c.discord.notify_player_best);
+        assert!(c.discord.notify_custom_spawnsets);
+
+        let ui = &c.ui_conf;
+        assert!(!ui.enabled);
+        assert!(ui.logo_style == LogoStyle::Mini);
+        assert!(ui.hide_logs);
+        assert_eq!(ui.logo.as_deref(), Some("custom"));
+        assert!(!ui.orb_connection_animation);
+        assert_eq!(ui.column_distance, 42);
+        assert!(!ui.show_help_on_border);
+        assert_eq!(ui.current_split_marker, "<");
+        assert!(!ui.current_split_live_change);
+        assert!(ui.always_show_splits);
+
+        let styles = &ui.theming.styles;
+        assert_eq!(styles.text, style(0));
+        assert_eq!(styles.logo, style(1));
+        assert_eq!(styles.logs, style(2));
+        assert_eq!(styles.logs_title, style(3));
+        assert_eq!(styles.log_text, style(4));
+        assert_eq!(styles.most_recent_log, style(5));
+        assert_eq!(styles.game_data, style(6));
+        assert_eq!(styles.game_data_title, style(7));
+        assert_eq!(styles.split_name, style(8));
+        assert_eq!(styles.accent, style(9));
+        assert_eq!(styles.split_diff_pos, style(10));
+        assert_eq!(styles.split_diff_neg, style(11));
+        assert_eq!(styles.split_diff_neutral, style(12));
+        assert_eq!(styles.split_diff_gold, style(13));
+
+        // Fields new in v1.1.0 get their defaults
+        assert!(!c.headless);
+        assert!(c.watch_config);
+        assert!(c.save_run_history);
+    }
+
+    #[test]
+    fn v1_0_0_homing_splits_become_profiles() {
+        let ui = parse_v1_0_0().ui_conf;
+
+        let profiles: Vec<(&str, &[Split])> = ui.split_profiles.iter().map(|p| (p.name.as_str(), &p.splits[..])).collect();
+        assert_eq!(profiles, vec![
+            ("Default", &[split("LEVI", 366., -105, 0, 5, None), split("700", 709., 2, 3, 4, Some(120))][..]),
+            ("Default 2", &[split("A", 100., 1, 2, 3, None)][..]),
+            ("Default 3", &[split("B", 200., 0, 0, 5, Some(7))][..]),
+        ]);
+        assert_eq!(ui.active_split_profile, "Default");
+
+        let m = &ui.game_data_modules;
+        assert_eq!(m.len(), 8);
+        assert!(matches!(m[0], GameDataModules::RunData));
+        assert!(matches!(m[1], GameDataModules::Homing(SizeStyle::Full)));
+        assert!(matches!(m[2], GameDataModules::ProfileSplits));
+        assert!(matches!(m[3], GameDataModules::Gems));
+        assert!(matches!(&m[4], GameDataModules::HomingSplits(s) if s[..] == [split("A", 100., 1, 2, 3, None)]));
+        assert!(matches!(m[5], GameDataModules::GemsLost(SizeStyle::Minimal)));
+        assert!(matches!(&m[6], GameDataModules::HomingSplits(s) if s[..] == [split("B", 200., 0, 0, 5, Some(7))]));
+        assert!(matches!(m[7], GameDataModules::Spacing));
+    }
+
+    #[test]
+    fn migrated_config_round_trips() {
+        let serialized = serialize_config(parse_v1_0_0()).unwrap();
+        let versioned: VersionedCfg = from_str(&serialized).expect("migrated config should parse");
+        assert!(outdated_version(&versioned).is_none());
+        assert_eq!(serialize_config(versioned.into()).unwrap(), serialized);
+    }
+
+    #[test]
+    fn upgrade_file_keeps_a_backup() {
+        let dir = scratch_dir("upgrade");
+        let path = dir.join("config.ron");
+        std::fs::write(&path, V1_0_0).unwrap();
+
+        let backup = upgrade_file(&path, "1.0.0", &parse_v1_0_0()).unwrap();
+
+        assert_eq!(backup, dir.join("config.v1_0_0.backup"));
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), V1_0_0);
+        let upgraded = parse_file(&path).unwrap();
+        assert!(outdated_version(&upgraded).is_none());
+        let upgraded: DDStatsRustConfig = upgraded.into();
+        assert_eq!(upgraded.host, "https://example.invalid");
+        assert_eq!(upgraded.ui_conf.split_profiles.len(), 3);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
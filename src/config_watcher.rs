@@ -0,0 +1,68 @@
+//
+//  config_watcher.rs - Reload config.ron when it changes on disk
+//
+
+use std::time::{Duration, SystemTime};
+use crate::threads::{AAS, State, Message};
+
+pub struct ConfigWatcher;
+
+impl ConfigWatcher {
+    pub async fn init(state: AAS<State>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            let mut last_modified = modified_time();
+
+            loop {
+                interval.tick().await;
+
+                if !crate::config::cfg().watch_config {
+                    continue;
+                }
+
+                let modified = modified_time();
+                if modified == last_modified {
+                    continue;
+                }
+                last_modified = modified;
+
+                if !changed_from_current() {
+                    continue;
+                }
+
+                let state = state.load();
+                let bus = &state.msg_bus.0;
+                match crate::config::reload() {
+                    Ok(warnings) => {
+                        log::info!("Reloaded config from disk");
+                        let _ = bus.send(Message::Log("Config Reloaded".to_string()));
+                        for w in warnings {
+                            let _ = bus.send(Message::Log(format!("Config Warning: {w}")));
+                        }
+                    },
+                    Err(e) => {
+                        log::warn!("Failed to reload config: {e:?}");
+                        let _ = bus.send(Message::Log(format!("Config Error: {e}")));
+                    }
+                }
+            }
+        });
+    }
+}
+
+fn modified_time() -> Option<SystemTime> {
+    std::fs::metadata(crate::config::get_priority_file()).and_then(|m| m.modified()).ok()
+}
+
+/// Our own saves (clr-set, set-modules...) also touch the file, those don't need a reload
+fn changed_from_current() -> bool {
+    let on_disk = match std::fs::read_to_string(crate::config::get_priority_file()) {
+        Ok(s) => s,
+        Err(_) => return false,
+    };
+
+    match crate::config::serialize_config((*crate::config::CONFIG.load_full()).clone()) {
+        Ok(current) => current.ne(&on_disk),
+        Err(_) => true,
+    }
+}
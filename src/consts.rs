@@ -2,6 +2,8 @@
 // consts
 //
 
+use std::time::Duration;
+
 pub const V3_SURVIVAL_HASH: &str = "569fead87abf4d30fdee4231a6398051";
 
 pub const VERSION: &str = "0.6.10";
@@ -43,7 +45,20 @@ ____________  _____ _____ ___ _____ _____
 |___/ |___/  \\____/  \\_/\\_| |_/\\_/ \\____/
 v4                                   rust";
 
-pub const SUBMIT_RETRY_MAX: u16 = 10;
+pub const SUBMIT_BACKOFF_MIN: Duration = Duration::from_secs(2);
+
+pub const SUBMIT_BACKOFF_MAX: Duration = Duration::from_secs(60 * 5);
+
+pub const REPLAY_UPLOAD_ATTEMPTS: u32 = 4;
+
+pub const REPLAY_UPLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Answers the server keeps failing on before a run moves to outbox/failed
+pub const SUBMIT_ATTEMPTS: u32 = 8;
+
+pub const SUBMIT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+pub const SUBMIT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
 
 //Linux
 #[cfg(target_os = "linux")]
@@ -2,96 +2,304 @@
 //  grpc_client.rs - I hate GRPC
 //
 
-use crate::{client::SubmitGameEvent, consts::{SUBMIT_RETRY_MAX, V3_SURVIVAL_HASH}, socketio_client::SubmitSioEvent, threads::{State, Message, AAS}, websocket_server::WsBroadcast};
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::{client::SubmitGameEvent, consts::{SUBMIT_ATTEMPTS, SUBMIT_BACKOFF_MAX, SUBMIT_BACKOFF_MIN, SUBMIT_CONNECT_TIMEOUT, SUBMIT_REQUEST_TIMEOUT, V3_SURVIVAL_HASH}, socketio_client::SubmitSioEvent, threads::{State, Message, AAS}, websocket_server::WsBroadcast};
+use crate::grpc_models::{game_recorder_client::GameRecorderClient, ClientStartRequest, StoredRun, SubmitGameRequest};
+use crate::submit_queue;
 use clipboard::{ClipboardContext, ClipboardProvider};
+use tokio::sync::Notify;
+use tokio::sync::broadcast::{error::RecvError, Receiver};
+use tonic::{transport::{Channel, Endpoint}, Code};
 
-pub struct GameSubmissionClient;
+pub struct GameSubmissionClient {
+    pub client: Option<GameRecorderClient<Channel>>,
+    pub backoff: Duration,
+    pub next_attempt: Instant,
+    pub last_depth: usize,
+    /// Submitted or rejected, but the outbox file couldn't be deleted yet
+    pub finished: HashSet<u64>,
+    /// Server errors for the run at the head of the queue
+    pub failures: (u64, u32),
+}
+
+enum DrainResult {
+    Submitted(u32),
+    Rejected,
+    /// The server answered with an error, might be fixed on its side later
+    Failed,
+    Unreachable,
+}
 
 impl GameSubmissionClient {
     pub async fn init(state: AAS<State>) {
+        let queued = Arc::new(Notify::new());
+
+        // Only writes the outbox, a slow host can't make this fall behind the bus and miss a run
+        let bus_recv = state.load().msg_bus.0.subscribe();
+        tokio::spawn(enqueue_runs(state.clone(), bus_recv, queued.clone()));
+
         tokio::spawn(async move {
-            use crate::grpc_models::game_recorder_client::GameRecorderClient;
-            use crate::grpc_models::{ClientStartRequest, SubmitGameRequest};
-            let cfg = crate::config::cfg();
-            let mut client = GameRecorderClient::connect(cfg.grpc_host.clone()).await.expect("No Connection");
-            let _res = client.client_start(ClientStartRequest { version: "0.6.10".to_owned() }).await;
-            let mut bus_recv = state.load().msg_bus.0.subscribe();
+            let mut drain_interval = tokio::time::interval(Duration::from_secs(1));
+            let mut c = Self {
+                client: None,
+                backoff: SUBMIT_BACKOFF_MIN,
+                next_attempt: Instant::now(),
+                last_depth: submit_queue::depth(),
+                finished: HashSet::new(),
+                failures: (0, 0),
+            };
+
+            if c.last_depth > 0 {
+                let _ = state.load().msg_bus.0.send(Message::Log(format!("{} Queued Runs", c.last_depth)));
+            }
 
             loop {
-                let state = state.load();
                 tokio::select! {
-                    msg = bus_recv.recv() => if let Ok(Message::SubmitGame(sge)) = msg {
-                        log::info!("Got submit request");
-                        if !should_submit(&sge) { continue; }
-
-                        let mut res = client.submit_game(SubmitGameRequest::from_compiled_run(sge.0.clone())).await;
-                        for _i in 0..SUBMIT_RETRY_MAX {
-                            if res.is_ok() { break; }
-                            res = client.submit_game(SubmitGameRequest::from_compiled_run(sge.0.clone())).await;
-                        }
-        
-                        if res.is_ok() {
-                            let res = res.as_ref().unwrap().get_ref();
-        
-                            let _ = state.msg_bus.0.send(Message::WebSocketMessage(WsBroadcast {
-                                _type: "ddstats_game_submit".into(),
-                                data: format!("{{ \"game_id\": {}, \"snowflake\": {} }}", res.game_id, sge.1)
-                            }));
-
-                            let _ = state.msg_bus.0.send(Message::Log(format!("Submitted {}", res.game_id)));
-        
-                            if cfg.auto_clipboard {
-                                let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-                                let new_clip = format!("{}/games/{}", cfg.host, res.game_id);
-                                ctx.set_contents(new_clip).unwrap();
-                            }
-        
-                            if should_submit_sio(&sge) {
-                                let _ = state.msg_bus.0.send(Message::SocketIoMessage(SubmitSioEvent { game_id: res.game_id }));
-                            }
-
-                            let should_upload = should_upload_replay(&sge);
-                            let game_id = res.game_id;
-                            let data_arc = sge.2.clone();
-                            tokio::spawn(async move {
-                                let replay_hash = format!("{:x}", ddcore_rs::md5::compute(&*data_arc));
-                                if ddcore_rs::ddreplay::create_ddstats_trace(game_id as u64, replay_hash).await.is_ok() {
-                                    log::info!("traced ddstats game: {}", game_id);
-                                    if should_upload {
-                                        let _ = state.msg_bus.0.send(Message::UploadReplayData(data_arc, false));
-                                    }
-                                }
-                            });
-                        } else {
-                            log::error!("Couldn't submit: {:?}", res);
-                            let _ = state.msg_bus.0.send(Message::Log("Failed to Submit".to_string()));
-                        }
+                    _queued = queued.notified() => {
+                        // New runs skip the wait, the host might be back already
+                        c.next_attempt = Instant::now();
+                        c.drain(&state).await;
                     },
+                    _elapsed = drain_interval.tick() => {
+                        c.drain(&state).await;
+                    }
                 };
             }
         });
     }
+
+    async fn drain(&mut self, state: &AAS<State>) {
+        if Instant::now() < self.next_attempt {
+            return;
+        }
+
+        while let Some(entry) = submit_queue::peek() {
+            // Never submit the same run twice, only the file is left to delete
+            if self.finished.contains(&entry.snowflake) {
+                if !self.remove(entry.snowflake) {
+                    break;
+                }
+                continue;
+            }
+
+            match self.try_submit(&entry).await {
+                DrainResult::Submitted(game_id) => {
+                    self.backoff = SUBMIT_BACKOFF_MIN;
+                    on_submitted(state, &entry, game_id);
+                    if !self.remove(entry.snowflake) {
+                        break;
+                    }
+                },
+                DrainResult::Rejected => {
+                    let _ = state.load().msg_bus.0.send(Message::Log("Submit Rejected".to_string()));
+                    if !self.remove(entry.snowflake) {
+                        break;
+                    }
+                },
+                DrainResult::Failed => {
+                    let attempts = if self.failures.0 == entry.snowflake { self.failures.1 + 1 } else { 1 };
+                    self.failures = (entry.snowflake, attempts);
+                    if attempts < SUBMIT_ATTEMPTS {
+                        self.wait_backoff();
+                        break;
+                    }
+
+                    // Don't hold up every later run behind one the server can't take
+                    log::error!("Giving up on run {} after {attempts} failed submissions", entry.snowflake);
+                    let _ = state.load().msg_bus.0.send(Message::Log("Submit Failed".to_string()));
+                    if let Err(e) = submit_queue::set_aside(entry.snowflake) {
+                        log::error!("Couldn't move run {} out of the outbox: {e:?}", entry.snowflake);
+                        self.wait_backoff();
+                        break;
+                    }
+                },
+                DrainResult::Unreachable => {
+                    self.wait_backoff();
+                    break;
+                },
+            }
+        }
+
+        self.report_depth(state);
+    }
+
+    fn wait_backoff(&mut self) {
+        self.next_attempt = Instant::now() + self.backoff;
+        log::warn!("Submission failed, retrying in {:?}", self.backoff);
+        self.backoff = (self.backoff * 2).min(SUBMIT_BACKOFF_MAX);
+    }
+
+    /// False when the entry is still in the outbox, draining waits out the backoff then
+    fn remove(&mut self, snowflake: u64) -> bool {
+        match submit_queue::remove(snowflake) {
+            Ok(()) => {
+                self.finished.remove(&snowflake);
+                true
+            },
+            Err(e) => {
+                log::error!("Couldn't remove submitted run {snowflake} from the outbox, retrying in {:?}: {e:?}", self.backoff);
+                self.finished.insert(snowflake);
+                self.next_attempt = Instant::now() + self.backoff;
+                false
+            },
+        }
+    }
+
+    async fn try_submit(&mut self, entry: &StoredRun) -> DrainResult {
+        let request = match &entry.run {
+            Some(run) => run.clone(),
+            None => return DrainResult::Rejected,
+        };
+
+        if self.client.is_none() {
+            match connect().await {
+                Ok(mut client) => {
+                    let _res = client.client_start(ClientStartRequest { version: crate::consts::VERSION.to_owned() }).await;
+                    self.client = Some(client);
+                },
+                Err(e) => {
+                    log::warn!("No connection to submission host: {:?}", e);
+                    return DrainResult::Unreachable;
+                }
+            }
+        }
+
+        let client = self.client.as_mut().unwrap();
+        match client.submit_game(request).await {
+            Ok(res) => DrainResult::Submitted(res.get_ref().game_id),
+            Err(status) => match status.code() {
+                Code::InvalidArgument | Code::FailedPrecondition | Code::AlreadyExists | Code::PermissionDenied => {
+                    log::error!("Submission rejected: {:?}", status);
+                    DrainResult::Rejected
+                },
+                Code::Unavailable | Code::DeadlineExceeded | Code::Cancelled => {
+                    log::error!("Couldn't submit: {:?}", status);
+                    self.client = None;
+                    DrainResult::Unreachable
+                },
+                _ => {
+                    log::error!("Submission failed: {:?}", status);
+                    self.client = None;
+                    DrainResult::Failed
+                }
+            }
+        }
+    }
+
+    fn report_depth(&mut self, state: &AAS<State>) {
+        let depth = submit_queue::depth();
+        if depth == self.last_depth {
+            return;
+        }
+
+        self.last_depth = depth;
+        let state = state.load();
+        let _ = state.msg_bus.0.send(Message::WebSocketMessage(WsBroadcast::SubmitQueue { depth }));
+
+        if depth > 0 {
+            let _ = state.msg_bus.0.send(Message::Log(format!("{} Queued Runs", depth)));
+        }
+    }
+}
+
+async fn enqueue_runs(state: AAS<State>, mut bus_recv: Receiver<Message>, queued: Arc<Notify>) {
+    loop {
+        let sge = match bus_recv.recv().await {
+            Ok(Message::SubmitGame(sge)) => sge,
+            Ok(_) => continue,
+            Err(RecvError::Lagged(n)) => {
+                log::error!("Submission queue missed {n} messages");
+                continue;
+            },
+            Err(RecvError::Closed) => break,
+        };
+
+        log::info!("Got submit request");
+        if !should_submit(&sge) { continue; }
+
+        if let Err(e) = submit_queue::enqueue(&sge) {
+            log::error!("Couldn't queue submission: {:?}", e);
+            let _ = state.load().msg_bus.0.send(Message::Log("Failed to Queue".to_string()));
+            continue;
+        }
+        queued.notify_one();
+    }
+}
+
+async fn connect() -> anyhow::Result<GameRecorderClient<Channel>> {
+    let channel = Endpoint::from_shared(crate::config::cfg().grpc_host.clone())?
+        .connect_timeout(SUBMIT_CONNECT_TIMEOUT)
+        .timeout(SUBMIT_REQUEST_TIMEOUT)
+        .connect()
+        .await?;
+    Ok(GameRecorderClient::new(channel))
+}
+
+fn on_submitted(state: &AAS<State>, entry: &StoredRun, game_id: u32) {
+    let state = state.load();
+    let cfg = crate::config::cfg();
+    let run = entry.run.as_ref().unwrap();
+
+    let _ = state.msg_bus.0.send(Message::WebSocketMessage(WsBroadcast::DdstatsGameSubmit { game_id, snowflake: entry.snowflake }));
+
+    let _ = state.msg_bus.0.send(Message::Log(format!("Submitted {}", game_id)));
+
+    if cfg.auto_clipboard {
+        // No display on headless hosts, the link is in the log either way
+        let ctx: Result<ClipboardContext, _> = ClipboardProvider::new();
+        match ctx {
+            Ok(mut ctx) => {
+                if let Err(e) = ctx.set_contents(format!("{}/games/{}", cfg.host, game_id)) {
+                    log::warn!("Couldn't copy the game link: {e:?}");
+                }
+            },
+            Err(e) => log::warn!("No clipboard: {e:?}"),
+        }
+    }
+
+    if should_submit_sio(run) {
+        let _ = state.msg_bus.0.send(Message::SocketIoMessage(SubmitSioEvent { game_id }));
+    }
+
+    if entry.replay.is_empty() {
+        return;
+    }
+
+    let should_upload = should_upload_replay(run);
+    let data_arc = Arc::new(entry.replay.clone());
+    let bus = state.msg_bus.0.clone();
+    tokio::spawn(async move {
+        let replay_hash = format!("{:x}", ddcore_rs::md5::compute(&*data_arc));
+        if ddcore_rs::ddreplay::create_ddstats_trace(game_id as u64, replay_hash).await.is_ok() {
+            log::info!("traced ddstats game: {}", game_id);
+            if should_upload {
+                let _ = bus.send(Message::UploadReplayData(data_arc, false));
+            }
+        }
+    });
 }
 
 #[rustfmt::skip]
-fn should_submit_sio(data: &SubmitGameEvent) -> bool {
+fn should_submit_sio(data: &SubmitGameRequest) -> bool {
     let cfg = crate::config::cfg();
-    let is_non_default = data.0.level_hash_md5.ne(&V3_SURVIVAL_HASH.to_uppercase());
+    let is_non_default = data.level_hash_md5.ne(&V3_SURVIVAL_HASH.to_uppercase());
     if is_non_default && !cfg.submit.non_default_spawnsets { return false; }
-    cfg.stream.stats && !data.0.is_replay
-    || cfg.stream.replay_stats && data.0.is_replay
+    cfg.stream.stats && !data.is_replay
+    || cfg.stream.replay_stats && data.is_replay
 }
 
 #[rustfmt::skip]
-fn should_upload_replay(data: &SubmitGameEvent) -> bool{
+fn should_upload_replay(data: &SubmitGameRequest) -> bool{
     let cfg = crate::config::cfg();
     if !cfg.upload_replays_automatically { return false; }
-    let is_non_default = data.0.level_hash_md5.ne(&V3_SURVIVAL_HASH.to_uppercase());
+    let is_non_default = data.level_hash_md5.ne(&V3_SURVIVAL_HASH.to_uppercase());
     if is_non_default { return false; }
-    if data.0.time_max < 100. { return false; }
-    if data.0.time_max < 500. && data.0.daggers_hit > 0 { return false; }
-    if data.0.is_replay && !cfg.submit.replay_stats { return false; }
-    if !data.0.is_replay && !cfg.submit.stats { return false; }
+    if data.time_max < 100. { return false; }
+    if data.time_max < 500. && data.daggers_hit > 0 { return false; }
+    if data.is_replay && !cfg.submit.replay_stats { return false; }
+    if !data.is_replay && !cfg.submit.stats { return false; }
     true
 }
 
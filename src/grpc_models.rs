@@ -26,6 +26,33 @@ impl StatsFrame {
             daggers_hit: other.daggers_hit,
         }
     }
+
+    pub fn to_game_frame(&self) -> ddcore_rs::models::StatsFrame {
+        let mut per_enemy_kill_count = [0i16; 17];
+        let mut per_enemy_alive_count = [0i16; 17];
+        for (i, x) in self.per_enemy_kill_count.iter().take(17).enumerate() {
+            per_enemy_kill_count[i] = *x as i16;
+        }
+        for (i, x) in self.per_enemy_alive_count.iter().take(17).enumerate() {
+            per_enemy_alive_count[i] = *x as i16;
+        }
+
+        ddcore_rs::models::StatsFrame {
+            gems_collected: self.gems_collected,
+            kills: self.kills,
+            daggers_fired: self.daggers_fired,
+            daggers_hit: self.daggers_hit,
+            enemies_alive: self.enemies_alive,
+            level_gems: self.level_gems,
+            homing: self.homing_daggers,
+            gems_despawned: self.gems_despawned,
+            gems_eaten: self.gems_eaten,
+            gems_total: self.gems_total,
+            daggers_eaten: self.daggers_eaten,
+            per_enemy_kill_count,
+            per_enemy_alive_count,
+        }
+    }
 }
 
 impl SubmitGameRequest {
@@ -0,0 +1,41 @@
+//
+//  headless.rs - Plain line output when running without the TUI
+//
+
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::threads::{AAS, State, Message};
+
+pub struct HeadlessLogThread;
+
+impl HeadlessLogThread {
+    pub async fn init(state: AAS<State>) {
+        tokio::spawn(async move {
+            let mut msg_bus = state.load().msg_bus.0.subscribe();
+
+            loop {
+                tokio::select! {
+                    msg = msg_bus.recv() => match msg {
+                        Ok(Message::Log(data)) => {
+                            log::info!("LOG: {:?}", data);
+                            print_line(&data);
+                        },
+                        Ok(Message::NewConnectionState(conn)) => print_line(&format!("Connection: {:?}", conn)),
+                        Ok(Message::Exit) => {
+                            print_line("Exiting");
+                            break;
+                        },
+                        _ => {},
+                    },
+                };
+            }
+        });
+    }
+}
+
+fn print_line(line: &str) {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let mut stdout = std::io::stdout();
+    let _ = writeln!(stdout, "[{now}] {line}");
+    let _ = stdout.flush();
+}
@@ -0,0 +1,220 @@
+//
+//  livesplit.rs - Drives a LiveSplit Server component from the game timer
+//
+
+use std::time::{Duration, Instant};
+use ddcore_rs::models::{GameStatus, StatsBlockWithFrames};
+use tokio::{io::AsyncWriteExt, net::TcpStream};
+use crate::config::LiveSplitEvent;
+use crate::threads::{AAS, State, Message};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+pub struct LiveSplitClient;
+
+struct Run {
+    snowflake: u128,
+    /// Per `split_on` entry, whether it was split already
+    split: Vec<bool>,
+    finished: bool,
+}
+
+impl LiveSplitClient {
+    pub async fn init(state: AAS<State>) {
+        tokio::spawn(async move {
+            let mut looper = tokio::time::interval(Duration::from_secs_f32(1. / 20.));
+            let mut conn: Option<TcpStream> = None;
+            let mut last_attempt: Option<Instant> = None;
+            let mut run: Option<Run> = None;
+
+            loop {
+                looper.tick().await;
+                let cfg = crate::config::cfg();
+
+                if !cfg.livesplit.enabled {
+                    conn = None;
+                    run = None;
+                    continue;
+                }
+
+                if conn.is_none() {
+                    if last_attempt.map_or(false, |t| t.elapsed() < RECONNECT_DELAY) {
+                        continue;
+                    }
+                    last_attempt = Some(Instant::now());
+                    match TcpStream::connect(&cfg.livesplit.address).await {
+                        Ok(stream) => {
+                            log::info!("Connected to LiveSplit at {}", cfg.livesplit.address);
+                            let _ = state.load().msg_bus.0.send(Message::Log("LiveSplit Connected".to_string()));
+                            conn = Some(stream);
+                            run = None;
+                        },
+                        Err(e) => {
+                            log::debug!("LiveSplit not reachable at {}: {e:?}", cfg.livesplit.address);
+                            continue;
+                        }
+                    }
+                }
+
+                let state = state.load();
+                let commands = next_commands(&state, &mut run, &cfg.livesplit.split_on);
+                if commands.is_empty() {
+                    continue;
+                }
+
+                let stream = conn.as_mut().unwrap();
+                if let Err(e) = stream.write_all(commands.concat().as_bytes()).await {
+                    log::warn!("Lost connection to LiveSplit: {e:?}");
+                    let _ = state.msg_bus.0.send(Message::Log("LiveSplit Disconnected".to_string()));
+                    conn = None;
+                    run = None;
+                }
+            }
+        });
+    }
+}
+
+/// Commands to send for the latest poll, every one terminated by "\r\n"
+fn next_commands(state: &State, run: &mut Option<Run>, split_on: &[LiveSplitEvent]) -> Vec<String> {
+    let data = &state.last_poll;
+    let status = data.block.status();
+    let mut commands = vec![];
+
+    if data.block.is_replay || !(status == GameStatus::Playing || status == GameStatus::Dead) {
+        return commands;
+    }
+
+    let is_new_run = run.as_ref().map_or(true, |r| r.snowflake != *state.snowflake);
+    if is_new_run {
+        if status != GameStatus::Playing {
+            return commands;
+        }
+        *run = Some(Run { snowflake: *state.snowflake, split: vec![false; split_on.len()], finished: false });
+        // Game time only moves through setgametime so it matches the in game timer exactly
+        commands.push("reset\r\n".to_string());
+        commands.push("starttimer\r\n".to_string());
+        commands.push("initgametime\r\n".to_string());
+        commands.push("pausegametime\r\n".to_string());
+    }
+
+    let run = run.as_mut().unwrap();
+    if run.finished {
+        return commands;
+    }
+
+    // Split in the order events happen, not the config order, so one that hasn't happened
+    // doesn't hold up the rest and game time never goes backwards
+    let mut happened: Vec<(usize, f32)> = split_on.iter()
+        .enumerate()
+        .filter(|(i, _)| !run.split.get(*i).copied().unwrap_or(true))
+        .filter_map(|(i, event)| event_time(data, *event).map(|time| (i, time)))
+        .collect();
+    happened.sort_by(|a, b| a.1.total_cmp(&b.1));
+    for (i, time) in happened {
+        commands.push(format!("setgametime {time:.4}\r\n"));
+        commands.push("split\r\n".to_string());
+        run.split[i] = true;
+    }
+
+    if status == GameStatus::Dead {
+        run.finished = true;
+        if run.split.contains(&false) {
+            commands.push(format!("setgametime {:.4}\r\n", data.block.time_max + data.block.starting_time));
+            commands.push("pause\r\n".to_string());
+        }
+    } else {
+        commands.push(format!("setgametime {:.4}\r\n", data.block.time_max + data.block.starting_time));
+    }
+
+    commands
+}
+
+/// In game time the event happened at, None if it hasn't happened yet
+fn event_time(data: &StatsBlockWithFrames, event: LiveSplitEvent) -> Option<f32> {
+    let block = &data.block;
+    let time = match event {
+        LiveSplitEvent::Level2 => block.time_lvl2,
+        LiveSplitEvent::Level3 => block.time_lvl3,
+        LiveSplitEvent::Level4 => block.time_lvl4,
+        LiveSplitEvent::LeviDown => block.levi_down_time,
+        LiveSplitEvent::OrbDown => block.orb_down_time,
+        LiveSplitEvent::Death => {
+            if block.status() != GameStatus::Dead { return None; }
+            return Some(block.time_max + block.starting_time);
+        },
+    };
+
+    if time > 0. { Some(time) } else { None }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use ddcore_rs::models::StatsDataBlock;
+    use super::*;
+
+    fn state(status: GameStatus, set: impl FnOnce(&mut StatsDataBlock)) -> State {
+        let mut data = StatsBlockWithFrames::default();
+        data.block.status = status as i32;
+        set(&mut data.block);
+        State {
+            conn: Arc::default(),
+            last_poll: Arc::new(data),
+            snowflake: Arc::new(1),
+            msg_bus: Arc::new(tokio::sync::broadcast::channel(1)),
+        }
+    }
+
+    fn splits(commands: &[String]) -> Vec<&str> {
+        commands.windows(2)
+            .filter(|pair| pair[1] == "split\r\n")
+            .map(|pair| pair[0].trim_end())
+            .collect()
+    }
+
+    #[test]
+    fn new_run_starts_the_timer() {
+        let mut run = None;
+
+        let commands = next_commands(&state(GameStatus::Playing, |b| b.time_max = 1.5), &mut run, &[LiveSplitEvent::Level2]);
+
+        assert_eq!(commands, ["reset\r\n", "starttimer\r\n", "initgametime\r\n", "pausegametime\r\n", "setgametime 1.5000\r\n"]);
+        assert!(next_commands(&state(GameStatus::Menu, |_| {}), &mut None, &[]).is_empty());
+        assert!(next_commands(&state(GameStatus::Playing, |b| b.is_replay = true), &mut None, &[]).is_empty());
+    }
+
+    #[test]
+    fn events_out_of_config_order_split_as_they_happen() {
+        let split_on = [LiveSplitEvent::LeviDown, LiveSplitEvent::Level4];
+        let mut run = None;
+
+        let commands = next_commands(&state(GameStatus::Playing, |b| { b.time_max = 90.; b.time_lvl4 = 80.; }), &mut run, &split_on);
+        assert_eq!(splits(&commands), ["setgametime 80.0000"]);
+
+        let commands = next_commands(&state(GameStatus::Playing, |b| { b.time_max = 200.; b.time_lvl4 = 80.; b.levi_down_time = 190.; }), &mut run, &split_on);
+        assert_eq!(splits(&commands), ["setgametime 190.0000"]);
+    }
+
+    #[test]
+    fn events_in_one_poll_split_in_time_order() {
+        let split_on = [LiveSplitEvent::Level3, LiveSplitEvent::Level2];
+        let mut run = None;
+
+        let commands = next_commands(&state(GameStatus::Playing, |b| { b.time_max = 100.; b.time_lvl2 = 50.; b.time_lvl3 = 90.; }), &mut run, &split_on);
+
+        assert_eq!(splits(&commands), ["setgametime 50.0000", "setgametime 90.0000"]);
+    }
+
+    #[test]
+    fn death_splits_once_and_pauses_when_splits_are_left() {
+        let split_on = [LiveSplitEvent::Level2, LiveSplitEvent::Death];
+        let mut run = None;
+        next_commands(&state(GameStatus::Playing, |b| b.time_max = 10.), &mut run, &split_on);
+
+        let commands = next_commands(&state(GameStatus::Dead, |b| b.time_max = 20.), &mut run, &split_on);
+
+        assert_eq!(splits(&commands), ["setgametime 20.0000"]);
+        assert_eq!(commands.last().unwrap(), "pause\r\n");
+        assert!(next_commands(&state(GameStatus::Dead, |b| b.time_max = 20.), &mut run, &split_on).is_empty());
+    }
+}
@@ -0,0 +1,86 @@
+//
+//  local_tls.rs - Certificate for the wss:// / https:// listener
+//
+//  Pages served over https can't open plain ws://, so the local server can also listen with TLS.
+//  Without a configured pair, a self-signed one is made once and kept in <config dir>/tls/
+//  (the browser has to be told to trust it once, by opening https://127.0.0.1:<port>/health-check)
+//
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use anyhow::{bail, Context};
+use rcgen::{DnType, SanType};
+use crate::config::LocalTls;
+
+pub struct CertPair {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+}
+
+fn generated_dir() -> PathBuf {
+    crate::config::get_config_dir().join("tls")
+}
+
+/// Loads the configured pair, or the generated one (making it first if needed)
+pub fn load_or_generate(tls: &LocalTls) -> anyhow::Result<CertPair> {
+    let (cert_path, key_path) = if tls.cert_path.is_empty() && tls.key_path.is_empty() {
+        let dir = generated_dir();
+        let paths = (dir.join("cert.pem"), dir.join("key.pem"));
+        if !paths.0.exists() || !paths.1.exists() {
+            generate(&paths.0, &paths.1)?;
+        }
+        paths
+    } else {
+        (PathBuf::from(&tls.cert_path), PathBuf::from(&tls.key_path))
+    };
+
+    let cert = std::fs::read(&cert_path).with_context(|| format!("Couldn't read {}", cert_path.display()))?;
+    let key = std::fs::read(&key_path).with_context(|| format!("Couldn't read {}", key_path.display()))?;
+
+    // warp panics on a bad pair, so catch the obvious mistakes here
+    if !contains_pem(&cert, "CERTIFICATE") {
+        bail!("{} is not a PEM certificate", cert_path.display());
+    }
+    if !contains_pem(&key, "PRIVATE KEY") {
+        bail!("{} is not a PEM private key", key_path.display());
+    }
+
+    Ok(CertPair { cert, key })
+}
+
+fn generate(cert_path: &Path, key_path: &Path) -> anyhow::Result<()> {
+    // IPs go in as IP SANs, browsers don't match 127.0.0.1 against a DNS name
+    let mut params = rcgen::CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, "ddstats-rust local server");
+    params.subject_alt_names = vec![
+        SanType::DnsName("localhost".to_string()),
+        SanType::IpAddress(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+        SanType::IpAddress(IpAddr::V6(Ipv6Addr::LOCALHOST)),
+    ];
+    let cert = rcgen::Certificate::from_params(params)?;
+
+    if let Some(dir) = cert_path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(cert_path, cert.serialize_pem()?)?;
+    write_private(key_path, cert.serialize_private_key_pem().as_bytes())?;
+    log::info!("Generated a self-signed certificate in {}", cert_path.display());
+    Ok(())
+}
+
+/// Only readable by the user, on Windows the config dir's ACL already is
+fn write_private(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(data)
+}
+
+fn contains_pem(data: &[u8], label: &str) -> bool {
+    String::from_utf8_lossy(data).lines().any(|l| l.starts_with("-----BEGIN") && l.contains(label))
+}
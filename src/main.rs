@@ -1,15 +1,32 @@
+pub mod cli;
 pub mod client;
 #[allow(unused_macros)]
 pub mod config;
+pub mod config_watcher;
 pub mod consts;
 pub mod grpc_client;
 pub mod grpc_models;
 pub mod threads;
 pub mod ui;
 pub mod websocket_server;
+pub mod ws_protocol;
+pub mod ws_subscriptions;
 pub mod socketio_client;
 pub mod discord;
 pub mod replay_recv;
+pub mod headless;
+pub mod run_history;
+pub mod replay_library;
+pub mod replay_upload;
+pub mod replay_store;
+pub mod reference_run;
+pub mod personal_best;
+pub mod livesplit;
+pub mod overlay;
+pub mod rest_api;
+pub mod server_auth;
+pub mod local_tls;
+pub mod submit_queue;
 #[cfg(target_os = "windows")] pub mod tray;
 
 #[tokio::main]
@@ -18,7 +35,7 @@ async fn main() {
     use simple_logging::log_to_file;
 
     // Setup Logs
-    if cfg().debug_logs && threads::port_is_available(18639) {
+    if cfg().debug_logs && threads::control_port_is_available() {
         log_to_file(config::get_log_file_path(), log::LevelFilter::Info).expect("Couldn't create logger!");
         log_panics::init();
     }
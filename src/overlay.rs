@@ -0,0 +1,205 @@
+//
+//  overlay.rs - Browser source overlays for OBS, served by the websocket server
+//
+//  /overlay/timer, /overlay/homing, /overlay/gems-lost, /overlay/splits
+//  Query string: label, size, font, align, bg, precision, rows, compare, token,
+//  and colour overrides (text, accent, pos, neg, neutral, gold) as hex without the #
+//
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::time::Duration;
+use ddcore_rs::models::StatsBlockWithFrames;
+use futures::StreamExt;
+use tokio::time::interval;
+use tokio_stream::wrappers::IntervalStream;
+use tui::style::Color;
+use warp::{Filter, Reply};
+use crate::config::{self, Styles};
+use crate::personal_best::PbComparison;
+use crate::threads::{AAS, State};
+use crate::ui::modules::{self, GameDataModules, SplitKind};
+
+const OVERLAY_HTML: &str = include_str!("../assets/overlay/overlay.html");
+const OVERLAY_JS: &str = include_str!("../assets/overlay/overlay.js");
+const WIDGETS: [&str; 4] = ["timer", "homing", "gems-lost", "splits"];
+
+#[derive(serde::Serialize)]
+struct OverlayFrame {
+    status: i32,
+    is_replay: bool,
+    time: f32,
+    homing: i32,
+    max_homing: i32,
+    max_homing_time: f32,
+    gems_collected: i32,
+    gems_despawned: i32,
+    gems_eaten: i32,
+    gems_lost: i32,
+    profile: Option<String>,
+    splits: Vec<OverlaySplit>,
+}
+
+#[derive(serde::Serialize, schemars::JsonSchema, Clone)]
+pub struct OverlaySplit {
+    pub name: String,
+    pub time: f32,
+    pub reached: bool,
+    pub live: bool,
+    pub homing: Option<i32>,
+    pub diff: Option<i32>,
+    pub kind: Option<SplitKind>,
+}
+
+pub fn routes(state: AAS<State>) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let js = warp::path!("overlay" / "overlay.js")
+        .map(|| warp::reply::with_header(OVERLAY_JS, "content-type", "application/javascript"));
+
+    let theme = warp::path!("overlay" / "theme.css")
+        .map(|| warp::reply::with_header(theme_css(&config::cfg().ui_conf.theming.styles), "content-type", "text/css"));
+
+    let data = warp::path!("overlay" / "data")
+        .and(crate::server_auth::read_access())
+        .and(warp::query::<HashMap<String, String>>())
+        .map(move |query: HashMap<String, String>| {
+            let state = state.clone();
+            let compare = query.get("compare").cloned();
+            let stream = IntervalStream::new(interval(Duration::from_secs_f32(1. / 20.))).map(move |_| {
+                let frame = OverlayFrame::from_state(&state.load(), compare.as_deref());
+                Ok::<_, Infallible>(warp::sse::Event::default().data(serde_json::to_string(&frame).unwrap()))
+            });
+            warp::sse::reply(warp::sse::keep_alive().stream(stream))
+        });
+
+    let page = warp::path!("overlay" / String)
+        .and_then(|widget: String| async move {
+            if !WIDGETS.contains(&widget.as_str()) {
+                return Err(warp::reject::not_found());
+            }
+            Ok(warp::reply::html(OVERLAY_HTML.replace("{{WIDGET}}", &widget)))
+        });
+
+    warp::get().and(js.or(theme).or(data).or(page))
+}
+
+impl OverlayFrame {
+    fn from_state(state: &State, compare: Option<&str>) -> Self {
+        let data = &state.last_poll;
+        let block = &data.block;
+        let (profile, splits) = current_splits(state, compare);
+
+        Self {
+            status: block.status,
+            is_replay: block.is_replay,
+            time: if block.is_replay { block.time } else { block.time_max + block.starting_time },
+            homing: data.frames.last().map_or(0, |f| f.homing),
+            max_homing: block.max_homing,
+            max_homing_time: if block.time_lvl3 == 0. { 0. } else { block.time_max_homing },
+            gems_collected: block.gems_collected,
+            gems_despawned: block.gems_despawned,
+            gems_eaten: block.gems_eaten,
+            gems_lost: block.gems_eaten + block.gems_despawned,
+            profile,
+            splits,
+        }
+    }
+}
+
+/// Name of the active split profile and how the current run does on it
+pub fn current_splits(state: &State, compare: Option<&str>) -> (Option<String>, Vec<OverlaySplit>) {
+    let cfg = config::cfg();
+    match config::active_split_profile(&cfg) {
+        Some(profile) => {
+            let compare = comparison(compare, &cfg.ui_conf.game_data_modules);
+            (Some(profile.name.clone()), overlay_splits(&state.last_poll, &profile.splits, compare))
+        },
+        None => (None, vec![]),
+    }
+}
+
+/// `?compare=` wins, otherwise whatever the PbSplits module in the UI uses
+fn comparison(compare: Option<&str>, game_data_modules: &[GameDataModules]) -> Option<PbComparison> {
+    match compare {
+        Some("BestRun") => Some(PbComparison::BestRun),
+        Some("SumOfBest") => Some(PbComparison::SumOfBest),
+        Some(_) => None,
+        None => game_data_modules.iter().find_map(|m| match m {
+            GameDataModules::PbSplits(c) => Some(*c),
+            _ => None,
+        }),
+    }
+}
+
+fn overlay_splits(data: &StatsBlockWithFrames, times: &[modules::Split], compare: Option<PbComparison>) -> Vec<OverlaySplit> {
+    let real_timer = data.block.time_max + data.block.starting_time;
+    let reference = compare.map(|c| {
+        let split_times: Vec<f32> = times.iter().map(|s| s.1).collect();
+        crate::personal_best::split_references(&data.block.level_hash(), c, &split_times)
+    });
+    let current = times.iter().position(|s| real_timer < s.1);
+
+    times.iter().enumerate().map(|(i, (name, time, ..))| {
+        let reached = real_timer >= *time && data.block.starting_time <= *time;
+        let live = current == Some(i) && data.block.is_in_game && !data.frames.is_empty();
+        if !(reached || live) || data.frames.is_empty() {
+            return OverlaySplit { name: name.clone(), time: *time, reached, live, homing: None, diff: None, kind: None };
+        }
+
+        let result = modules::evaluate_split(data, times, i, reference.as_deref().map(|r| &r[..]));
+        OverlaySplit {
+            name: name.clone(),
+            time: *time,
+            reached,
+            live,
+            homing: Some(result.homing),
+            diff: result.diff,
+            kind: Some(result.kind),
+        }
+    }).collect()
+}
+
+fn theme_css(styles: &Styles) -> String {
+    let vars = [
+        ("text", styles.text),
+        ("accent", styles.accent),
+        ("background", styles.game_data),
+        ("title", styles.game_data_title),
+        ("split-name", styles.split_name),
+        ("pos", styles.split_diff_pos),
+        ("neg", styles.split_diff_neg),
+        ("neutral", styles.split_diff_neutral),
+        ("gold", styles.split_diff_gold),
+    ];
+
+    let mut css = String::from(":root {\n");
+    for (name, style) in vars {
+        css.push_str(&format!("    --{name}: {};\n", css_color(style.fg, "inherit")));
+        css.push_str(&format!("    --{name}-bg: {};\n", css_color(style.bg, "transparent")));
+    }
+    css.push_str("}\n");
+    css
+}
+
+fn css_color(color: Option<Color>, fallback: &str) -> String {
+    let hex = match color {
+        Some(Color::Black) => "#000000",
+        Some(Color::Red) => "#cd3131",
+        Some(Color::Green) => "#0dbc79",
+        Some(Color::Yellow) => "#e5e510",
+        Some(Color::Blue) => "#2472c8",
+        Some(Color::Magenta) => "#bc3fbc",
+        Some(Color::Cyan) => "#11a8cd",
+        Some(Color::Gray) => "#e5e5e5",
+        Some(Color::DarkGray) => "#666666",
+        Some(Color::LightRed) => "#f14c4c",
+        Some(Color::LightGreen) => "#23d18b",
+        Some(Color::LightYellow) => "#f5f543",
+        Some(Color::LightBlue) => "#3b8eea",
+        Some(Color::LightMagenta) => "#d670d6",
+        Some(Color::LightCyan) => "#29b8db",
+        Some(Color::White) => "#ffffff",
+        Some(Color::Rgb(r, g, b)) => return format!("#{r:02x}{g:02x}{b:02x}"),
+        Some(Color::Indexed(_)) | Some(Color::Reset) | None => fallback,
+    };
+    hex.to_string()
+}
@@ -0,0 +1,217 @@
+//
+//  personal_best.rs - Split references built from the local run history
+//
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use ddcore_rs::models::StatsBlockWithFrames;
+use lazy_static::lazy_static;
+use crate::grpc_models::{StoredRun, SubmitGameRequest};
+
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum PbComparison {
+    BestRun,   // Homing of the longest run at each split
+    SumOfBest, // Best homing gain ever seen per segment, added up
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SplitReference {
+    /// Reference homing count at the split time
+    pub homing: Option<i32>,
+    /// Best homing gain ever made in the segment ending at this split
+    pub best_segment: Option<i32>,
+}
+
+type ReferenceKey = (String, PbComparison, Vec<u32>);
+
+lazy_static! {
+    static ref INDEX: Mutex<RunIndex> = Mutex::new(RunIndex::default());
+    static ref REFERENCES: Mutex<HashMap<ReferenceKey, Arc<Vec<SplitReference>>>> = Mutex::new(HashMap::new());
+}
+
+/// Frames of every non replay history run by spawnset, the replays themselves are never decoded
+#[derive(Default)]
+struct RunIndex {
+    loaded: bool,
+    snowflakes: HashSet<u64>,
+    by_spawnset: HashMap<String, Vec<StatsBlockWithFrames>>,
+}
+
+impl RunIndex {
+    fn insert(&mut self, snowflake: u64, run: Option<&SubmitGameRequest>, data: StatsBlockWithFrames) {
+        let run = match run {
+            Some(run) if !run.is_replay && !data.frames.is_empty() => run,
+            _ => return,
+        };
+        // A run saved while the history was loading shows up in both
+        if self.snowflakes.insert(snowflake) {
+            self.by_spawnset.entry(run.level_hash_md5.to_uppercase()).or_default().push(data);
+        }
+    }
+}
+
+/// Reads the whole history once, blocking, run it off the UI and server threads.
+/// Until it's done every reference is empty
+pub fn load_index() {
+    let runs: Vec<_> = crate::run_history::load_all_run_frames()
+        .into_iter()
+        .map(|run| {
+            let data = run.to_stats_block_with_frames();
+            (run.snowflake, run.run, data)
+        })
+        .collect();
+    let count = runs.len();
+
+    let mut index = INDEX.lock().unwrap();
+    for (snowflake, run, data) in runs {
+        index.insert(snowflake, run.as_ref(), data);
+    }
+    index.loaded = true;
+    drop(index);
+
+    REFERENCES.lock().unwrap().clear();
+    log::info!("Indexed {count} history runs for splits");
+}
+
+/// Adds a freshly saved run without reading the history again
+pub fn add_run(run: &StoredRun) {
+    INDEX.lock().unwrap().insert(run.snowflake, run.run.as_ref(), run.to_stats_block_with_frames());
+    REFERENCES.lock().unwrap().clear();
+}
+
+/// References for every split time on the given spawnset, cached until the history changes
+pub fn split_references(level_hash: &str, comparison: PbComparison, times: &[f32]) -> Arc<Vec<SplitReference>> {
+    let key = (level_hash.to_owned(), comparison, times.iter().map(|t| t.to_bits()).collect());
+    if let Some(refs) = REFERENCES.lock().unwrap().get(&key) {
+        return refs.clone();
+    }
+
+    let index = INDEX.lock().unwrap();
+    if !index.loaded {
+        return Arc::new(vec![SplitReference::default(); times.len()]);
+    }
+    let runs = index.by_spawnset.get(&level_hash.to_uppercase()).map_or(&[][..], |runs| &runs[..]);
+    let refs = Arc::new(match comparison {
+        PbComparison::BestRun => best_run_references(runs, times),
+        PbComparison::SumOfBest => sum_of_best_references(runs, times),
+    });
+    // Still holding the index, so a run added meanwhile can't be cleared before this lands
+    REFERENCES.lock().unwrap().insert(key, refs.clone());
+    refs
+}
+
+/// Homing of a run at the given time, None if the run died before it
+fn homing_at(run: &StatsBlockWithFrames, time: f32) -> Option<i32> {
+    if run.block.time_max + run.block.starting_time < time {
+        return None;
+    }
+    run.get_frame_for_time(time).map(|f| f.homing)
+}
+
+fn segment_gain(run: &StatsBlockWithFrames, times: &[f32], i: usize) -> Option<i32> {
+    let end = homing_at(run, times[i])?;
+    let start = if i == 0 { 0 } else { homing_at(run, times[i - 1])? };
+    Some(end - start)
+}
+
+fn best_segments(runs: &[StatsBlockWithFrames], times: &[f32]) -> Vec<Option<i32>> {
+    (0..times.len())
+        .map(|i| runs.iter().filter_map(|run| segment_gain(run, times, i)).max())
+        .collect()
+}
+
+fn best_run_references(runs: &[StatsBlockWithFrames], times: &[f32]) -> Vec<SplitReference> {
+    let best = runs.iter().max_by(|a, b| {
+        (a.block.time_max + a.block.starting_time).total_cmp(&(b.block.time_max + b.block.starting_time))
+    });
+
+    best_segments(runs, times)
+        .into_iter()
+        .zip(times)
+        .map(|(best_segment, time)| SplitReference {
+            homing: best.and_then(|run| homing_at(run, *time)),
+            best_segment,
+        })
+        .collect()
+}
+
+fn sum_of_best_references(runs: &[StatsBlockWithFrames], times: &[f32]) -> Vec<SplitReference> {
+    let mut total = Some(0);
+    best_segments(runs, times)
+        .into_iter()
+        .map(|best_segment| {
+            total = total.zip(best_segment).map(|(a, b)| a + b);
+            SplitReference { homing: total, best_segment }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ddcore_rs::models::StatsFrame;
+
+    /// One frame per second with the given homing counts
+    fn run(time_max: f32, homing: &[i32]) -> StatsBlockWithFrames {
+        let mut data = StatsBlockWithFrames {
+            frames: homing.iter().map(|&homing| StatsFrame { homing, ..Default::default() }).collect(),
+            ..Default::default()
+        };
+        data.block.time_max = time_max;
+        data
+    }
+
+    fn homing(refs: &[SplitReference]) -> Vec<Option<i32>> {
+        refs.iter().map(|r| r.homing).collect()
+    }
+
+    fn best_segment(refs: &[SplitReference]) -> Vec<Option<i32>> {
+        refs.iter().map(|r| r.best_segment).collect()
+    }
+
+    #[test]
+    fn homing_at_stops_at_death() {
+        let short = run(2.5, &[0, 30, 35]);
+
+        assert_eq!(homing_at(&short, 1.), Some(30));
+        assert_eq!(homing_at(&short, 2.), Some(35));
+        assert_eq!(homing_at(&short, 3.), None);
+        assert_eq!(segment_gain(&short, &[1., 2., 3.], 1), Some(5));
+        assert_eq!(segment_gain(&short, &[1., 2., 3.], 2), None);
+    }
+
+    #[test]
+    fn best_run_follows_the_longest_run() {
+        let runs = [run(4.5, &[0, 10, 20, 30, 40]), run(2.5, &[0, 30, 35])];
+        let refs = best_run_references(&runs, &[1., 2., 3.]);
+
+        assert_eq!(homing(&refs), [Some(10), Some(20), Some(30)]);
+        assert_eq!(best_segment(&refs), [Some(30), Some(10), Some(10)]);
+    }
+
+    #[test]
+    fn sum_of_best_adds_up_segments_across_runs() {
+        let runs = [run(4.5, &[0, 10, 20, 30, 40]), run(2.5, &[0, 30, 35])];
+        let refs = sum_of_best_references(&runs, &[1., 2., 3., 10.]);
+
+        assert_eq!(best_segment(&refs), [Some(30), Some(10), Some(10), None]);
+        // Nobody reached the last split, so there is nothing to add up past it
+        assert_eq!(homing(&refs), [Some(30), Some(40), Some(50), None]);
+        assert!(sum_of_best_references(&[], &[1.]).iter().all(|r| r.homing.is_none()));
+    }
+
+    #[test]
+    fn index_skips_replays_and_duplicates() {
+        let mut index = RunIndex::default();
+        let submit = SubmitGameRequest { level_hash_md5: "abc".into(), ..Default::default() };
+        let replay = SubmitGameRequest { is_replay: true, ..submit.clone() };
+
+        index.insert(1, Some(&submit), run(1.5, &[0, 1]));
+        index.insert(1, Some(&submit), run(1.5, &[0, 1]));
+        index.insert(2, Some(&replay), run(1.5, &[0, 1]));
+        index.insert(3, Some(&submit), run(0., &[]));
+        index.insert(4, None, run(1.5, &[0, 1]));
+
+        assert_eq!(index.by_spawnset["ABC"].len(), 1);
+    }
+}
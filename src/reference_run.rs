@@ -0,0 +1,279 @@
+//
+//  reference_run.rs - The run the live game or a replay is compared against
+//
+//  Replay files only carry inputs, so a replay's per second stats come from the
+//  history entry saved when it was played (matched by md5, then by its header).
+//
+
+use std::sync::{Arc, Mutex};
+use ddcore_rs::models::{StatsBlockWithFrames, StatsFrame};
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::Deserialize;
+use crate::grpc_models::StoredRun;
+use crate::replay_library::ReplayHeader;
+
+lazy_static! {
+    static ref REFERENCE: Mutex<Option<Arc<ReferenceRun>>> = Mutex::new(None);
+}
+
+pub struct ReferenceRun {
+    pub label: String,
+    pub data: StatsBlockWithFrames,
+}
+
+#[derive(Deserialize, JsonSchema, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ReferenceSource {
+    /// Snowflake of a run in the local history
+    History(u64),
+    /// File name from the replay library
+    Replay(String),
+    /// Longest run in the history on the spawnset being played
+    Best,
+}
+
+pub fn current() -> Option<Arc<ReferenceRun>> {
+    REFERENCE.lock().unwrap().clone()
+}
+
+pub fn clear() {
+    *REFERENCE.lock().unwrap() = None;
+}
+
+/// Loads and selects a reference, returns its label
+pub fn select(source: &ReferenceSource, level_hash: &str) -> anyhow::Result<String> {
+    let (label, run) = match source {
+        ReferenceSource::History(snowflake) => {
+            let run = crate::run_history::load_run(*snowflake)?;
+            (run_label(&run), run)
+        },
+        ReferenceSource::Replay(file_name) => {
+            let path = crate::replay_library::path_of(file_name)?;
+            let replay = crate::client::get_replay_file_content(path.to_string_lossy().into_owned())?;
+            let run = find_replay_run(&replay)
+                .ok_or_else(|| anyhow::anyhow!("No stats for {file_name}, play it once so its frames are recorded"))?;
+            (format!("{} (replay)", run_label(&run)), run)
+        },
+        ReferenceSource::Best => {
+            let run = crate::run_history::load_all_runs()
+                .into_iter()
+                .filter(|run| run.run.as_ref().map_or(false, |r| !r.is_replay && r.level_hash_md5.eq_ignore_ascii_case(level_hash)))
+                .max_by(|a, b| run_time(a).total_cmp(&run_time(b)))
+                .ok_or_else(|| anyhow::anyhow!("No runs on this spawnset in the history"))?;
+            (format!("{} (best)", run_label(&run)), run)
+        },
+    };
+
+    let data = run.to_stats_block_with_frames();
+    if data.frames.is_empty() {
+        anyhow::bail!("{label} has no frames");
+    }
+
+    *REFERENCE.lock().unwrap() = Some(Arc::new(ReferenceRun { label: label.clone(), data }));
+    Ok(label)
+}
+
+fn run_time(run: &StoredRun) -> f32 {
+    run.run.as_ref().map_or(0., |r| r.time_max) + run.starting_time
+}
+
+fn run_label(run: &StoredRun) -> String {
+    let name = run.run.as_ref().map_or_else(String::new, |r| r.player_name.clone());
+    format!("{name} {:.4}", run_time(run))
+}
+
+fn find_replay_run(replay: &[u8]) -> Option<StoredRun> {
+    let md5 = ddcore_rs::md5::compute(replay);
+    let header = ReplayHeader::parse(replay).ok();
+    let runs = crate::run_history::load_all_runs();
+
+    if let Some(pos) = runs.iter().position(|run| !run.replay.is_empty() && ddcore_rs::md5::compute(&run.replay) == md5) {
+        return runs.into_iter().nth(pos);
+    }
+
+    // Same run recorded from memory can differ in bytes, the header still matches
+    let header = header?;
+    runs.into_iter().find(|run| match ReplayHeader::parse(&run.replay) {
+        Ok(other) => other.player_id == header.player_id
+            && other.spawnset_hash == header.spawnset_hash
+            && (other.time - header.time).abs() < 0.001,
+        Err(_) => false,
+    })
+}
+
+/// Homing, gems, kills and enemies alive of a frame
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameValues {
+    pub homing: i32,
+    pub gems: i32,
+    pub kills: i32,
+    pub enemies_alive: i32,
+}
+
+pub fn values_at(run: &StatsBlockWithFrames, time: f32) -> Option<FrameValues> {
+    run.get_frame_for_time(time).map(frame_values)
+}
+
+fn frame_values(f: &StatsFrame) -> FrameValues {
+    FrameValues {
+        homing: f.homing,
+        gems: f.gems_collected,
+        kills: f.kills,
+        enemies_alive: f.enemies_alive,
+    }
+}
+
+/// Latest values of the live block, these move every frame unlike the per second frames
+pub fn live_values(live: &StatsBlockWithFrames) -> FrameValues {
+    FrameValues {
+        homing: live.block.homing,
+        gems: live.block.gems_collected,
+        kills: live.block.kills,
+        enemies_alive: live.block.enemies_alive,
+    }
+}
+
+/// Reference values at `time`, None once the reference run is over
+pub fn reference_values(reference: &StatsBlockWithFrames, time: f32) -> Option<FrameValues> {
+    if reference.block.time_max + reference.block.starting_time < time {
+        return None;
+    }
+    values_at(reference, time)
+}
+
+/// Reference values at `time` between its per second frames, for comparing against the live block.
+/// The last second runs up to the values at death
+pub fn interpolated_values(reference: &StatsBlockWithFrames, time: f32) -> Option<FrameValues> {
+    let end = reference.block.time_max;
+    let real_time = time - reference.block.starting_time;
+    if !(0. ..=end).contains(&real_time) {
+        return None;
+    }
+
+    let second = real_time.floor();
+    let before = frame_values(reference.frames.get(second as usize)?);
+    let (after, after_time) = match reference.frames.get(second as usize + 1) {
+        Some(frame) => (frame_values(frame), second + 1.),
+        None => (live_values(reference), end),
+    };
+    let t = if after_time > second { ((real_time - second) / (after_time - second)).min(1.) } else { 0. };
+    let lerp = |a: i32, b: i32| a + ((b - a) as f32 * t).round() as i32;
+
+    Some(FrameValues {
+        homing: lerp(before.homing, after.homing),
+        gems: lerp(before.gems, after.gems),
+        kills: lerp(before.kills, after.kills),
+        enemies_alive: lerp(before.enemies_alive, after.enemies_alive),
+    })
+}
+
+/// Reference values and live minus reference at one second of the run, None where the reference was already dead
+#[derive(Clone, Copy, Debug)]
+pub struct SecondDelta {
+    pub time: f32,
+    pub reference: Option<FrameValues>,
+    pub delta: Option<FrameValues>,
+}
+
+/// Deltas for the last `count` seconds the live run has frames for
+pub fn second_deltas(live: &StatsBlockWithFrames, reference: &StatsBlockWithFrames, count: usize) -> Vec<SecondDelta> {
+    let end = live.frames.len();
+    let start = end.saturating_sub(count);
+    (start..end)
+        .map(|i| {
+            let time = live.block.starting_time + i as f32;
+            let theirs = reference_values(reference, time);
+            let delta = values_at(live, time)
+                .zip(theirs)
+                .map(|(ours, theirs)| diff(ours, theirs));
+            SecondDelta { time, reference: theirs, delta }
+        })
+        .collect()
+}
+
+pub fn diff(ours: FrameValues, theirs: FrameValues) -> FrameValues {
+    FrameValues {
+        homing: ours.homing - theirs.homing,
+        gems: ours.gems - theirs.gems,
+        kills: ours.kills - theirs.kills,
+        enemies_alive: ours.enemies_alive - theirs.enemies_alive,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frame `i` has homing `i * 10`, gems `i`, kills `i * 2`, enemies alive `i * 3`
+    fn run(starting_time: f32, time_max: f32, frames: usize) -> StatsBlockWithFrames {
+        let mut data = StatsBlockWithFrames {
+            frames: (0..frames as i32)
+                .map(|i| StatsFrame { homing: i * 10, gems_collected: i, kills: i * 2, enemies_alive: i * 3, ..Default::default() })
+                .collect(),
+            ..Default::default()
+        };
+        data.block.starting_time = starting_time;
+        data.block.time_max = time_max;
+        // Values at death, past the last per second frame
+        let last = frames as i32;
+        data.block.homing = last * 10;
+        data.block.gems_collected = last;
+        data.block.kills = last * 2;
+        data.block.enemies_alive = last * 3;
+        data
+    }
+
+    #[test]
+    fn interpolated_values_between_frames() {
+        let reference = run(0., 3.5, 4);
+
+        assert_eq!(interpolated_values(&reference, 1.).unwrap().homing, 10);
+        assert_eq!(interpolated_values(&reference, 1.5).unwrap().homing, 15);
+        assert_eq!(interpolated_values(&reference, 2.25).unwrap(), FrameValues { homing: 23, gems: 2, kills: 5, enemies_alive: 7 });
+    }
+
+    #[test]
+    fn interpolated_values_at_both_ends() {
+        let reference = run(10., 3.5, 4);
+
+        assert_eq!(interpolated_values(&reference, 9.9), None);
+        assert_eq!(interpolated_values(&reference, 10.).unwrap().homing, 0);
+        // The last second runs from the last frame to the values at death
+        assert_eq!(interpolated_values(&reference, 13.25).unwrap().homing, 35);
+        assert_eq!(interpolated_values(&reference, 13.5).unwrap().homing, 40);
+        assert_eq!(interpolated_values(&reference, 13.6), None);
+    }
+
+    #[test]
+    fn second_deltas_at_both_ends() {
+        let live = run(0., 5.5, 6);
+        let mut reference = run(0., 3.5, 4);
+        for frame in &mut reference.frames {
+            frame.homing -= 1;
+        }
+
+        let deltas = second_deltas(&live, &reference, 10);
+
+        assert_eq!(deltas.iter().map(|d| d.time).collect::<Vec<_>>(), [0., 1., 2., 3., 4., 5.]);
+        // No frame for second 0 of either run, the reference is dead after 3.5
+        assert!(deltas[0].delta.is_none());
+        assert_eq!(deltas[1].delta.unwrap().homing, 1);
+        assert_eq!(deltas[3].reference.unwrap().homing, 29);
+        assert_eq!(deltas[3].delta.unwrap(), FrameValues { homing: 1, gems: 0, kills: 0, enemies_alive: 0 });
+        assert!(deltas[4].reference.is_none() && deltas[4].delta.is_none());
+        assert!(deltas[5].delta.is_none());
+    }
+
+    #[test]
+    fn second_deltas_keep_the_last_count_seconds() {
+        let live = run(0., 5.5, 6);
+        let reference = run(0., 10., 11);
+
+        let deltas = second_deltas(&live, &reference, 2);
+
+        assert_eq!(deltas.iter().map(|d| d.time).collect::<Vec<_>>(), [4., 5.]);
+        assert_eq!(deltas[1].delta.unwrap(), FrameValues::default());
+        assert!(second_deltas(&run(0., 0., 0), &reference, 5).is_empty());
+    }
+}
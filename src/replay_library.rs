@@ -0,0 +1,466 @@
+//
+//  replay_library.rs - Indexed folder of .ddreplay files
+//
+//  Only the header is read for the index:
+//      "ddrpl." | version i32 | timestamp i64 | time f32 | start time f32 | daggers fired i32
+//      | death type i32 | gems i32 | daggers hit i32 | kills i32 | player id i32
+//      | username length i32 | username | 10 unknown bytes | spawnset md5 [u8; 16] | ...
+//
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use anyhow::{bail, Context};
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+pub const REPLAY_FILE_EXTENSION: &str = "ddreplay";
+const MAGIC: &[u8] = b"ddrpl.";
+// Release date of the game, anything older is not a real timestamp
+const MIN_TIMESTAMP: u64 = 1455753600;
+
+lazy_static! {
+    static ref INDEX: Mutex<HashMap<PathBuf, (SystemTime, ReplayEntry)>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Serialize, JsonSchema, Clone, Debug)]
+pub struct ReplayEntry {
+    pub file_name: String,
+    pub player_name: String,
+    pub player_id: i32,
+    pub time: f32,
+    pub start_time: f32,
+    pub spawnset_hash: String,
+    /// Unix seconds, from the header or the file if the header has none
+    pub date: u64,
+}
+
+#[derive(Debug)]
+pub struct ReplayHeader {
+    pub timestamp: i64,
+    pub time: f32,
+    pub start_time: f32,
+    pub player_id: i32,
+    pub player_name: String,
+    pub spawnset_hash: String,
+}
+
+impl ReplayHeader {
+    pub fn parse(data: &[u8]) -> anyhow::Result<Self> {
+        let mut r = HeaderReader { data, pos: 0 };
+        if r.take(MAGIC.len())? != MAGIC {
+            bail!("Not a replay file");
+        }
+        let _version = r.i32()?;
+        let timestamp = r.i64()?;
+        let time = r.f32()?;
+        let start_time = r.f32()?;
+        let _daggers_fired = r.i32()?;
+        let _death_type = r.i32()?;
+        let _gems = r.i32()?;
+        let _daggers_hit = r.i32()?;
+        let _kills = r.i32()?;
+        let player_id = r.i32()?;
+        let name_len = r.i32()?;
+        if !(0..=256).contains(&name_len) {
+            bail!("Bad username length {name_len}");
+        }
+        let player_name = String::from_utf8_lossy(r.take(name_len as usize)?).into_owned();
+        r.take(10)?;
+        let spawnset_hash = r.take(16)?.iter().fold(String::with_capacity(32), |mut hex, b| {
+            let _ = write!(hex, "{b:02X}");
+            hex
+        });
+
+        Ok(Self { timestamp, time, start_time, player_id, player_name, spawnset_hash })
+    }
+}
+
+struct HeaderReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> HeaderReader<'a> {
+    fn take(&mut self, n: usize) -> anyhow::Result<&'a [u8]> {
+        let out = self.data.get(self.pos..self.pos + n).context("Replay header is cut short")?;
+        self.pos += n;
+        Ok(out)
+    }
+
+    fn i32(&mut self) -> anyhow::Result<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn i64(&mut self) -> anyhow::Result<i64> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn f32(&mut self) -> anyhow::Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+}
+
+pub fn library_dir() -> PathBuf {
+    crate::config::cfg().replay_library.dir()
+}
+
+/// Every replay in the library and one level of subfolders (auto saves), newest first
+pub fn scan() -> Vec<ReplayEntry> {
+    let dir = library_dir();
+    let mut files = replay_files(&dir);
+    if let Ok(entries) = std::fs::read_dir(&dir) {
+        for sub in entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_dir()) {
+            files.extend(replay_files(&sub));
+        }
+    }
+
+    let mut out: Vec<ReplayEntry> = index_files(&dir, files).into_iter().map(|(_, entry)| entry).collect();
+    out.sort_by(|a, b| b.date.cmp(&a.date).then_with(|| a.file_name.cmp(&b.file_name)));
+    out
+}
+
+fn replay_files(dir: &Path) -> Vec<PathBuf> {
+    match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().map_or(false, |ext| ext == REPLAY_FILE_EXTENSION))
+            .collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Entries for `files`, named relative to `base`. Unchanged files come from the index
+fn index_files(base: &Path, files: Vec<PathBuf>) -> Vec<(PathBuf, ReplayEntry)> {
+    let mut index = INDEX.lock().unwrap();
+    index.retain(|path, _| path.exists());
+
+    let mut out = vec![];
+    for path in files {
+        let modified = match std::fs::metadata(&path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        let mut entry = match index.get(&path) {
+            Some((indexed, entry)) if *indexed == modified => entry.clone(),
+            _ => match read_entry(&path, modified) {
+                Ok(entry) => {
+                    index.insert(path.clone(), (modified, entry.clone()));
+                    entry
+                },
+                Err(e) => {
+                    log::warn!("Skipping replay {path:?}: {e:?}");
+                    continue;
+                },
+            },
+        };
+        entry.file_name = relative_name(base, &path);
+        out.push((path, entry));
+    }
+    out
+}
+
+fn relative_name(base: &Path, path: &Path) -> String {
+    let rel = path.strip_prefix(base).unwrap_or(path);
+    rel.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+}
+
+/// Whitespace separated terms, each has to match the player, file name or spawnset hash
+pub fn search(query: &str) -> Vec<ReplayEntry> {
+    let terms: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    scan().into_iter()
+        .filter(|entry| terms.iter().all(|term| {
+            entry.player_name.to_lowercase().contains(term)
+                || entry.file_name.to_lowercase().contains(term)
+                || entry.spawnset_hash.to_lowercase().starts_with(term)
+        }))
+        .collect()
+}
+
+/// Full path of a replay in the library, from its `file_name` (relative, "/" separated)
+pub fn path_of(file_name: &str) -> anyhow::Result<PathBuf> {
+    let parts: Vec<&str> = file_name.split('/').collect();
+    if parts.iter().any(|p| p.is_empty() || p.starts_with('.') || p.contains(['\\', ':'])) || parts.len() > 2 {
+        bail!("Not a library file name: {file_name:?}");
+    }
+    let path = parts.iter().fold(library_dir(), |dir, part| dir.join(part));
+    if !path.is_file() {
+        bail!("No replay named {file_name} in the library");
+    }
+    Ok(path)
+}
+
+/// Writes a replay into the library, named after its header
+pub fn save_replay(replay: &[u8]) -> anyhow::Result<PathBuf> {
+    let header = ReplayHeader::parse(replay)?;
+    let dir = library_dir();
+    std::fs::create_dir_all(&dir)?;
+
+    let date = header_date(&header).unwrap_or_else(now);
+    let file_name = format!("{}_{:.4}_{}.{REPLAY_FILE_EXTENSION}", sanitize(&header.player_name), header.time + header.start_time, date);
+    let path = dir.join(file_name);
+    if path.exists() {
+        return Ok(path);
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let mut f = File::create(&tmp_path)?;
+    f.write_all(replay)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+    Ok(path)
+}
+
+/// Writes the replay of a finished run to the auto save folder, then applies the retention rules
+/// to the older files, the one just written is always kept
+pub fn auto_save(replay: &[u8]) -> anyhow::Result<PathBuf> {
+    let cfg = crate::config::cfg();
+    let rules = &cfg.replay_library.auto_save;
+    let header = ReplayHeader::parse(replay)?;
+    let dir = cfg.replay_library.auto_save_dir();
+    std::fs::create_dir_all(&dir)?;
+
+    let stem = fill_template(&rules.file_template, &header);
+    let mut path = dir.join(format!("{stem}.{REPLAY_FILE_EXTENSION}"));
+    let mut n = 2;
+    while path.exists() {
+        path = dir.join(format!("{stem}_{n}.{REPLAY_FILE_EXTENSION}"));
+        n += 1;
+    }
+
+    let tmp_path = path.with_extension("tmp");
+    let mut f = File::create(&tmp_path)?;
+    f.write_all(replay)?;
+    f.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+
+    for removed in apply_retention(&dir, &path, rules.keep_top_per_spawnset, rules.keep_above_seconds) {
+        log::info!("Retention removed {removed:?}");
+    }
+    Ok(path)
+}
+
+fn fill_template(template: &str, header: &ReplayHeader) -> String {
+    let date = header_date(header).unwrap_or_else(now);
+    let time = format!("{:.4}", header.time + header.start_time);
+    let name = template
+        .replace("{player}", &sanitize(&header.player_name))
+        .replace("{player_id}", &header.player_id.to_string())
+        .replace("{time}", &time)
+        .replace("{spawnset}", &header.spawnset_hash.chars().take(8).collect::<String>())
+        .replace("{date}", &format_date(date))
+        .replace("{timestamp}", &date.to_string())
+        .replace(['/', '\\', ':'], "_");
+    if name.trim().is_empty() { sanitize(&header.player_name) } else { name }
+}
+
+/// Deletes replays in `dir` that no rule keeps, except `exempt`. With both rules off nothing is deleted
+fn apply_retention(dir: &Path, exempt: &Path, keep_top: usize, keep_above: f32) -> Vec<PathBuf> {
+    if keep_top == 0 && keep_above <= 0. {
+        return vec![];
+    }
+
+    let mut by_spawnset: HashMap<String, Vec<(PathBuf, f32)>> = HashMap::new();
+    for (path, entry) in index_files(dir, replay_files(dir)) {
+        by_spawnset.entry(entry.spawnset_hash).or_default().push((path, entry.time + entry.start_time));
+    }
+
+    let mut removed = vec![];
+    for runs in by_spawnset.values_mut() {
+        runs.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        for (rank, (path, time)) in runs.iter().enumerate() {
+            let kept = (keep_top > 0 && rank < keep_top) || (keep_above > 0. && *time >= keep_above);
+            if kept || path == exempt {
+                continue;
+            }
+            match std::fs::remove_file(path) {
+                Ok(_) => removed.push(path.clone()),
+                Err(e) => log::warn!("Couldn't remove {path:?}: {e:?}"),
+            }
+        }
+    }
+    removed
+}
+
+fn read_entry(path: &Path, modified: SystemTime) -> anyhow::Result<ReplayEntry> {
+    // The header is well under 1KB, the rest of the file is the compressed replay
+    let mut buf = vec![];
+    File::open(path)?.take(1024).read_to_end(&mut buf)?;
+    let header = ReplayHeader::parse(&buf)?;
+
+    let date = header_date(&header)
+        .unwrap_or_else(|| modified.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()));
+
+    Ok(ReplayEntry {
+        file_name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+        player_name: header.player_name,
+        player_id: header.player_id,
+        time: header.time,
+        start_time: header.start_time,
+        spawnset_hash: header.spawnset_hash,
+        date,
+    })
+}
+
+fn header_date(header: &ReplayHeader) -> Option<u64> {
+    let ts = u64::try_from(header.timestamp).ok()?;
+    if (MIN_TIMESTAMP..=now() + 86400).contains(&ts) { Some(ts) } else { None }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+pub fn sanitize(name: &str) -> String {
+    let clean: String = name.chars()
+        .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    if clean.is_empty() { "unknown".to_string() } else { clean }
+}
+
+/// YYYY-MM-DD of a unix timestamp (UTC)
+pub fn format_date(secs: u64) -> String {
+    // civil_from_days, http://howardhinnant.github.io/date_algorithms.html
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{y:04}-{m:02}-{d:02}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_bytes(name: &str, time: f32, start_time: f32, timestamp: i64, spawnset: u8) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.extend(1i32.to_le_bytes());
+        out.extend(timestamp.to_le_bytes());
+        out.extend(time.to_le_bytes());
+        out.extend(start_time.to_le_bytes());
+        for value in [100i32, 1, 50, 40, 30] {
+            out.extend(value.to_le_bytes());
+        }
+        out.extend(229624i32.to_le_bytes());
+        out.extend((name.len() as i32).to_le_bytes());
+        out.extend(name.as_bytes());
+        out.extend([0u8; 10]);
+        out.extend([spawnset; 16]);
+        out
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ddstats-rust-test-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parse_reads_the_header() {
+        let header = ReplayHeader::parse(&header_bytes("xvlv", 500.25, 0.5, 1600000000, 0xAB)).unwrap();
+
+        assert_eq!(header.timestamp, 1600000000);
+        assert_eq!(header.time, 500.25);
+        assert_eq!(header.start_time, 0.5);
+        assert_eq!(header.player_id, 229624);
+        assert_eq!(header.player_name, "xvlv");
+        assert_eq!(header.spawnset_hash, "AB".repeat(16));
+    }
+
+    #[test]
+    fn parse_rejects_short_and_bad_buffers() {
+        let full = header_bytes("xvlv", 500., 0., 1600000000, 0);
+
+        assert!(ReplayHeader::parse(&[]).is_err());
+        assert!(ReplayHeader::parse(b"ddrpl").is_err());
+        assert!(ReplayHeader::parse(b"notrpl.0000000000000000000").is_err());
+        assert!(ReplayHeader::parse(&full[..full.len() - 1]).is_err());
+
+        let mut bad_len = full.clone();
+        let len_at = MAGIC.len() + 4 + 8 + 4 + 4 + 5 * 4 + 4;
+        bad_len[len_at..len_at + 4].copy_from_slice(&(-1i32).to_le_bytes());
+        assert!(ReplayHeader::parse(&bad_len).is_err());
+    }
+
+    #[test]
+    fn format_date_is_utc_calendar_date() {
+        assert_eq!(format_date(0), "1970-01-01");
+        assert_eq!(format_date(951782400), "2000-02-29");
+        assert_eq!(format_date(1455753600), "2016-02-18");
+        assert_eq!(format_date(1600000000), "2020-09-13");
+    }
+
+    #[test]
+    fn fill_template_replaces_every_field() {
+        let header = ReplayHeader::parse(&header_bytes("x/v lv", 500.25, 0.5, 1600000000, 0xAB)).unwrap();
+
+        assert_eq!(
+            fill_template("{player}_{player_id}_{time}_{spawnset}_{date}_{timestamp}", &header),
+            "x_v_lv_229624_500.7500_ABABABAB_2020-09-13_1600000000",
+        );
+        assert_eq!(fill_template("runs/{player}:best", &header), "runs_x_v_lv_best");
+        assert_eq!(fill_template("  ", &header), "x_v_lv");
+    }
+
+    fn write_replays(dir: &Path, runs: &[(&str, f32, u8)]) -> Vec<PathBuf> {
+        runs.iter()
+            .map(|(name, time, spawnset)| {
+                let path = dir.join(format!("{name}.{REPLAY_FILE_EXTENSION}"));
+                std::fs::write(&path, header_bytes(name, *time, 0., 1600000000, *spawnset)).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn remaining(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = replay_files(dir).iter()
+            .map(|p| p.file_stem().unwrap().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn apply_retention_keeps_top_per_spawnset() {
+        let dir = scratch_dir("retention-top");
+        let paths = write_replays(&dir, &[("a10", 10., 1), ("a20", 20., 1), ("a30", 30., 1), ("a40", 40., 1), ("b5", 5., 2)]);
+
+        let removed = apply_retention(&dir, &paths[4], 2, 0.);
+
+        assert_eq!(removed.len(), 2);
+        assert_eq!(remaining(&dir), ["a30", "a40", "b5"]);
+    }
+
+    #[test]
+    fn apply_retention_never_removes_the_exempt_file() {
+        let dir = scratch_dir("retention-exempt");
+        let paths = write_replays(&dir, &[("a10", 10., 1), ("a20", 20., 1), ("a30", 30., 1)]);
+
+        let removed = apply_retention(&dir, &paths[0], 1, 0.);
+
+        assert_eq!(removed, [paths[1].clone()]);
+        assert_eq!(remaining(&dir), ["a10", "a30"]);
+    }
+
+    #[test]
+    fn apply_retention_keeps_runs_above_the_time() {
+        let dir = scratch_dir("retention-above");
+        let paths = write_replays(&dir, &[("a10", 10., 1), ("a20", 20., 1), ("a30", 30., 1)]);
+
+        assert!(apply_retention(&dir, &paths[0], 0, 0.).is_empty());
+        apply_retention(&dir, &paths[0], 0, 25.);
+        assert_eq!(remaining(&dir), ["a10", "a30"]);
+    }
+}
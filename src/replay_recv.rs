@@ -1,45 +1,235 @@
 //
-//  replay_recv.rs - local replay receiver (Open With...)
+//  replay_recv.rs - local replay receiver (Open With...) and control channel
 //
+//  Requests and responses are single lines of JSON:
+//      -> { "id": 1, "method": "status", "params": null }
+//      <- { "id": 1, "result": { ... } }
+//      <- { "id": 1, "error": { "code": -32601, "message": "..." } }
+//
+//  Only loopback peers are served unless local_server.allow_lan is on,
+//  and then requests from other machines need "token" set to local_server.token
+//
+
+use std::{time::Duration, sync::Arc};
+use serde_json::Value;
+use tokio::{net::{TcpListener, TcpStream}, io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader}};
+use crate::{client::ConnectionState, threads::{AAS, State, Message}};
 
-use std::{time::Duration, net::Ipv4Addr};
-use tokio::{net::{TcpListener, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}};
-use crate::threads::{AAS, State};
+pub const ERR_PARSE: i32 = -32700;
+pub const ERR_METHOD_NOT_FOUND: i32 = -32601;
+pub const ERR_INVALID_PARAMS: i32 = -32602;
+pub const ERR_FAILED: i32 = -32000;
+pub const ERR_UNAUTHORIZED: i32 = -32001;
+
+/// Longest request line, anything longer drops the connection before it's parsed
+const MAX_LINE_LEN: u64 = 16 * 1024;
+
+/// A peer that sends nothing for this long is dropped
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
 
 #[derive(serde::Serialize, serde::Deserialize)]
 pub struct LocalFileReplayMsg {
     pub path: String
 }
 
-async fn process_socket(mut socket: TcpStream, state: AAS<State>) {
-    if socket.peer_addr().expect("No peer IP address").ip().ne(&Ipv4Addr::LOCALHOST) { return; }
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ControlRequest {
+    pub id: u64,
+    pub method: String,
+    #[serde(default)]
+    pub params: Value,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ControlResponse {
+    pub id: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ControlError>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct ControlError {
+    pub code: i32,
+    pub message: String,
+}
+
+#[derive(serde::Deserialize)]
+struct PathParams {
+    path: String,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct StatusReply {
+    pub version: String,
+    pub connection: ConnectionState,
+    pub player_name: String,
+    pub game_status: i32,
+    pub time: f32,
+    pub snowflake: u128,
+    pub submit_queue: usize,
+}
+
+impl StatusReply {
+    pub fn from_state(state: &State) -> Self {
+        Self {
+            version: crate::consts::PKG_VERSION.to_owned(),
+            connection: (*state.conn).clone(),
+            player_name: state.last_poll.block.player_username(),
+            game_status: state.last_poll.block.status,
+            time: state.last_poll.block.time_max + state.last_poll.block.starting_time,
+            snowflake: *state.snowflake,
+            submit_queue: crate::submit_queue::depth(),
+        }
+    }
+}
+
+impl ControlResponse {
+    fn ok(id: u64, result: Value) -> Self {
+        Self { id, result: Some(result), error: None }
+    }
+
+    fn err(id: u64, code: i32, message: String) -> Self {
+        Self { id, result: None, error: Some(ControlError { code, message }) }
+    }
+}
 
-    let _ = socket.readable().await;
-    tokio::time::interval(Duration::from_millis(50)).tick().await;
-    let mut txt_buf = [0u8; 2000];
-    if let Ok(_read) = socket.read(&mut txt_buf).await {
-        if let Ok(read_as_string) = String::from_utf8(txt_buf.to_vec()) {
-            let read_as_string = read_as_string.trim_matches(char::from(0)).to_owned();
-            let r: Result<LocalFileReplayMsg, serde_json::Error> = serde_json::from_str(&read_as_string);
-            if r.is_err() {
-                return;
+async fn process_socket(socket: TcpStream, state: AAS<State>) {
+    let remote = match socket.peer_addr() {
+        Ok(peer) => !peer.ip().is_loopback(),
+        Err(_) => return,
+    };
+    if remote && !crate::config::cfg().local_server.allow_lan { return; }
+
+    let (reader, mut writer) = socket.into_split();
+    let mut reader = BufReader::new(reader);
+
+    while let Some(line) = next_line(&mut reader).await {
+        let line = line.trim_matches(char::from(0)).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        // Older versions send a bare { "path": ... } and hang up without waiting for a reply
+        // they carry no token, so only from this machine
+        if let Some(legacy) = legacy_request(line) {
+            if !remote {
+                let _ = state.load().msg_bus.0.send(Message::PlayReplayLocalFile(legacy.path));
             }
-            let r = r.unwrap();
-            let _ = state.load().msg_bus.0.send(crate::threads::Message::PlayReplayLocalFile(r.path));
+            continue;
+        }
+
+        let response = match serde_json::from_str::<ControlRequest>(line) {
+            Ok(req) if remote && !req.token.as_deref().map_or(false, crate::server_auth::is_valid_token) => {
+                ControlResponse::err(req.id, ERR_UNAUTHORIZED, "Missing or wrong token".to_string())
+            },
+            Ok(req) => handle_request(req, &state),
+            Err(e) => ControlResponse::err(0, ERR_PARSE, format!("{e}")),
+        };
+
+        let mut out = serde_json::to_string(&response).unwrap();
+        out.push('\n');
+        if writer.write_all(out.as_bytes()).await.is_err() {
+            break;
         }
     }
 }
 
+/// Only lines that aren't a request, one with a method goes through the normal checks
+fn legacy_request(line: &str) -> Option<LocalFileReplayMsg> {
+    let value = serde_json::from_str::<Value>(line).ok()?;
+    if value.get("method").is_some() || value.get("jsonrpc").is_some() {
+        return None;
+    }
+    serde_json::from_value(value).ok()
+}
+
+/// None when the peer hung up, went quiet for IDLE_TIMEOUT, sent something that isn't UTF-8 or went over MAX_LINE_LEN
+async fn next_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Option<String> {
+    let mut buf = Vec::new();
+    let read = match tokio::time::timeout(IDLE_TIMEOUT, (&mut *reader).take(MAX_LINE_LEN + 1).read_until(b'\n', &mut buf)).await {
+        Ok(read) => read.ok()?,
+        Err(_) => {
+            log::info!("Control connection idle for {IDLE_TIMEOUT:?}, dropping it");
+            return None;
+        },
+    };
+    if read == 0 {
+        return None;
+    }
+    if buf.last() == Some(&b'\n') {
+        buf.pop();
+    }
+    if buf.len() as u64 > MAX_LINE_LEN {
+        log::warn!("Control request over {MAX_LINE_LEN} bytes, dropping the connection");
+        return None;
+    }
+    String::from_utf8(buf).ok()
+}
+
+fn handle_request(req: ControlRequest, state: &AAS<State>) -> ControlResponse {
+    let state = state.load();
+    let bus = &state.msg_bus.0;
+    match req.method.as_str() {
+        "play_replay" => match serde_json::from_value::<PathParams>(req.params) {
+            Ok(p) => {
+                let _ = bus.send(Message::PlayReplayLocalFile(p.path));
+                ControlResponse::ok(req.id, Value::Null)
+            },
+            Err(e) => ControlResponse::err(req.id, ERR_INVALID_PARAMS, format!("{e}")),
+        },
+        "upload_replay" => match serde_json::from_value::<PathParams>(req.params) {
+            Ok(p) => match crate::client::get_replay_file_content(p.path) {
+                Ok(replay) => {
+                    let _ = bus.send(Message::UploadReplayData(Arc::new(replay), true));
+                    ControlResponse::ok(req.id, Value::Null)
+                },
+                Err(e) => ControlResponse::err(req.id, ERR_FAILED, format!("{e}")),
+            },
+            Err(e) => ControlResponse::err(req.id, ERR_INVALID_PARAMS, format!("{e}")),
+        },
+        "show_window" => {
+            let _ = bus.send(Message::ShowWindow);
+            ControlResponse::ok(req.id, Value::Null)
+        },
+        "hide_window" => {
+            let _ = bus.send(Message::HideWindow);
+            ControlResponse::ok(req.id, Value::Null)
+        },
+        "reload_config" => match crate::config::reload() {
+            Ok(warnings) => {
+                let _ = bus.send(Message::Log("Config Reloaded".to_string()));
+                for w in &warnings {
+                    let _ = bus.send(Message::Log(format!("Config Warning: {w}")));
+                }
+                ControlResponse::ok(req.id, serde_json::json!({ "warnings": warnings }))
+            },
+            Err(e) => ControlResponse::err(req.id, ERR_FAILED, format!("{e:?}")),
+        },
+        "status" => ControlResponse::ok(req.id, serde_json::to_value(StatusReply::from_state(&state)).unwrap()),
+        "quit" => {
+            let _ = bus.send(Message::Exit);
+            ControlResponse::ok(req.id, Value::Null)
+        },
+        other => ControlResponse::err(req.id, ERR_METHOD_NOT_FOUND, format!("Unknown method: {other}")),
+    }
+}
+
 pub struct LocalReplayReceiver;
 
 impl LocalReplayReceiver {
     pub async fn init(state: AAS<State>) {
         tokio::spawn(async move {
-            let listener = TcpListener::bind("127.0.0.1:18639").await.unwrap();
+            let addr = crate::config::cfg().local_server.control_addr();
+            log::info!("replay receiver listening on: {}", addr);
+            let listener = TcpListener::bind(addr).await.unwrap();
 
             loop {
                 match listener.accept().await {
-                    Ok((socket, _)) => process_socket(socket, state.clone()).await,
+                    Ok((socket, _)) => { tokio::spawn(process_socket(socket, state.clone())); },
                     Err(_e) => {}
                 }
             }
@@ -47,14 +237,34 @@ impl LocalReplayReceiver {
     }
 }
 
-pub async fn send_to_current_instance(path: String) {
-    let j = serde_json::json!({
-        "path": path
-    }).to_string();
+/// Sends a request to the running instance and waits for its result
+pub async fn request_current_instance(method: &str, params: Value) -> anyhow::Result<Value> {
+    let cfg = crate::config::cfg();
+    let connection = TcpStream::connect(cfg.local_server.control_connect_addr()).await?;
+    let (reader, mut writer) = connection.into_split();
+
+    let mut req = serde_json::to_string(&ControlRequest {
+        id: 1,
+        method: method.to_owned(),
+        params,
+        token: Some(cfg.local_server.token.clone()).filter(|t| !t.is_empty()),
+    })?;
+    req.push('\n');
+    writer.write_all(req.as_bytes()).await?;
+    writer.flush().await?;
+
+    let mut lines = BufReader::new(reader).lines();
+    let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line()).await??;
+    let line = line.ok_or_else(|| anyhow::anyhow!("Connection closed without a reply"))?;
+    let response: ControlResponse = serde_json::from_str(&line)?;
 
-    if let Ok(mut connection) = TcpStream::connect("127.0.0.1:18639").await {
-        let _ = connection.writable().await;
-        let _ = connection.try_write(j.as_bytes());
-        let _ = connection.flush();
+    match response.error {
+        Some(e) => anyhow::bail!("{} ({})", e.message, e.code),
+        None => Ok(response.result.unwrap_or(Value::Null)),
     }
 }
+
+pub async fn send_to_current_instance(path: String) -> anyhow::Result<()> {
+    request_current_instance("play_replay", serde_json::json!({ "path": path })).await?;
+    Ok(())
+}
@@ -0,0 +1,292 @@
+//
+//  replay_store.rs - Where uploaded replays live and how they're linked
+//
+//  Picked with `replay_store` in the config. Every store names replays by the
+//  md5 of the file, the same hash the ddreplay service uses.
+//
+
+use std::path::PathBuf;
+use std::sync::Arc;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use ddcore_rs::client_https;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use crate::config::ReplayStoreConfig;
+use crate::replay_library::REPLAY_FILE_EXTENSION;
+use crate::replay_upload::UploadError;
+
+pub trait ReplayStore: Send + Sync {
+    /// Short name, part of the upload dedupe key
+    fn name(&self) -> &'static str;
+    fn upload(&self, data: Arc<Vec<u8>>, md5: String, manual: bool) -> BoxFuture<'static, Result<(), UploadError>>;
+    /// What gets copied to the clipboard and sent to websocket clients
+    fn link(&self, md5: &str) -> String;
+    fn fetch(&self, md5: String) -> BoxFuture<'static, anyhow::Result<Vec<u8>>>;
+}
+
+pub fn current() -> Box<dyn ReplayStore> {
+    match crate::config::cfg().replay_store.clone() {
+        ReplayStoreConfig::DdReplay { link_base } => Box::new(DdReplayStore { link_base }),
+        ReplayStoreConfig::LocalHttp { dir, public_url } => Box::new(LocalHttpStore {
+            dir: hosted_dir(&dir),
+            public_url,
+        }),
+        ReplayStoreConfig::Directory { dir, link_base } => Box::new(DirectoryStore { dir: PathBuf::from(dir), link_base }),
+    }
+}
+
+pub struct DdReplayStore {
+    link_base: String,
+}
+
+impl ReplayStore for DdReplayStore {
+    fn name(&self) -> &'static str {
+        "ddreplay"
+    }
+
+    fn upload(&self, data: Arc<Vec<u8>>, _md5: String, manual: bool) -> BoxFuture<'static, Result<(), UploadError>> {
+        async move {
+            match upload_ddreplay(data, manual).await {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    log::info!("Failed replay upload: {e:?}");
+                    Err(crate::replay_upload::classify(&e))
+                },
+            }
+        }.boxed()
+    }
+
+    fn link(&self, md5: &str) -> String {
+        format!("{}{md5}", self.link_base)
+    }
+
+    fn fetch(&self, md5: String) -> BoxFuture<'static, anyhow::Result<Vec<u8>>> {
+        let link = self.link(&md5);
+        async move { crate::websocket_server::get_replay_link(&link).await }.boxed()
+    }
+}
+
+/// The server answered, but not with a 200
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl std::fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// Same request as ddcore_rs::ddreplay::upload_replay, which drops the status code
+async fn upload_ddreplay(data: Arc<Vec<u8>>, manual: bool) -> anyhow::Result<()> {
+    let client: Client<_, Body> = client_https!();
+    let body = serde_json::json!({ "data": base64::encode(&*data), "manual": manual });
+    let req = Request::builder()
+        .header("content-type", "application/json")
+        .header("accept", "application/json")
+        .method(Method::POST)
+        .uri("https://ddreplay.herokuapp.com/upload")
+        .body(Body::from(serde_json::to_string(&body)?))?;
+    let res = client.request(req).await?;
+    let status = res.status();
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    if status != StatusCode::OK {
+        return Err(HttpStatusError { status, body: String::from_utf8_lossy(&body).into_owned() }.into());
+    }
+    Ok(())
+}
+
+/// Kept on disk and served by the local server at /hosted/<md5>.ddreplay
+pub struct LocalHttpStore {
+    dir: PathBuf,
+    public_url: String,
+}
+
+impl ReplayStore for LocalHttpStore {
+    fn name(&self) -> &'static str {
+        "local_http"
+    }
+
+    fn upload(&self, data: Arc<Vec<u8>>, md5: String, _manual: bool) -> BoxFuture<'static, Result<(), UploadError>> {
+        let path = self.dir.join(file_name(&md5));
+        async move { write_new(path, data).await }.boxed()
+    }
+
+    fn link(&self, md5: &str) -> String {
+        let base = if self.public_url.is_empty() {
+            let mut addr = crate::config::cfg().local_server.server_addr();
+            if addr.ip().is_unspecified() {
+                addr.set_ip(std::net::Ipv4Addr::LOCALHOST.into());
+            }
+            format!("http://{addr}")
+        } else {
+            self.public_url.trim_end_matches('/').to_string()
+        };
+        format!("{base}/hosted/{}", file_name(md5))
+    }
+
+    fn fetch(&self, md5: String) -> BoxFuture<'static, anyhow::Result<Vec<u8>>> {
+        let path = self.dir.join(file_name(&md5));
+        async move { Ok(tokio::fs::read(path).await?) }.boxed()
+    }
+}
+
+/// Copied into a folder the user shares some other way
+pub struct DirectoryStore {
+    dir: PathBuf,
+    link_base: String,
+}
+
+impl ReplayStore for DirectoryStore {
+    fn name(&self) -> &'static str {
+        "directory"
+    }
+
+    fn upload(&self, data: Arc<Vec<u8>>, md5: String, _manual: bool) -> BoxFuture<'static, Result<(), UploadError>> {
+        let path = self.dir.join(file_name(&md5));
+        async move { write_new(path, data).await }.boxed()
+    }
+
+    fn link(&self, md5: &str) -> String {
+        if self.link_base.is_empty() {
+            self.dir.join(file_name(md5)).to_string_lossy().into_owned()
+        } else {
+            format!("{}{}", self.link_base, file_name(md5))
+        }
+    }
+
+    fn fetch(&self, md5: String) -> BoxFuture<'static, anyhow::Result<Vec<u8>>> {
+        let path = self.dir.join(file_name(&md5));
+        async move { Ok(tokio::fs::read(path).await?) }.boxed()
+    }
+}
+
+pub fn hosted_dir(dir: &str) -> PathBuf {
+    if dir.is_empty() {
+        crate::config::get_config_dir().join("hosted")
+    } else {
+        PathBuf::from(dir)
+    }
+}
+
+fn file_name(md5: &str) -> String {
+    format!("{md5}.{REPLAY_FILE_EXTENSION}")
+}
+
+/// True for the md5 names stores use, so a link or path can't point anywhere else
+pub fn is_md5(s: &str) -> bool {
+    s.len() == 32 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+async fn write_new(path: PathBuf, data: Arc<Vec<u8>>) -> Result<(), UploadError> {
+    if path.exists() {
+        return Err(UploadError::Exists);
+    }
+    let tmp_path = path.with_extension("tmp");
+    let res = async {
+        if let Some(dir) = path.parent() {
+            tokio::fs::create_dir_all(dir).await?;
+        }
+        tokio::fs::write(&tmp_path, &data[..]).await?;
+        tokio::fs::rename(&tmp_path, &path).await
+    }.await;
+
+    res.map_err(|e| {
+        log::error!("Couldn't store replay at {path:?}: {e:?}");
+        // A missing share or full disk is worth trying again, no permission isn't
+        crate::replay_upload::classify(&e.into())
+    })
+}
+
+/// Replay bytes for a replay_link request: an md5 or a link of the configured store
+/// goes through the store, any other url is downloaded as is
+pub async fn fetch_link(link: &str) -> anyhow::Result<Vec<u8>> {
+    let store = current();
+    let link = link.trim();
+
+    match md5_of_link(store.as_ref(), link) {
+        Some(md5) => store.fetch(md5).await,
+        None => crate::websocket_server::get_replay_link(link).await,
+    }
+}
+
+/// The md5 of a bare md5 or one of `store`'s links, lowercase
+fn md5_of_link(store: &dyn ReplayStore, link: &str) -> Option<String> {
+    if is_md5(link) {
+        return Some(link.to_lowercase());
+    }
+
+    // Everything in front of the md5 in this store's links
+    let placeholder = "0".repeat(32);
+    let sample = store.link(&placeholder);
+    let prefix = &sample[..sample.find(&placeholder).unwrap_or(sample.len())];
+
+    link.strip_prefix(prefix)
+        .map(|rest| rest.trim_end_matches(&format!(".{REPLAY_FILE_EXTENSION}")))
+        .filter(|md5| is_md5(md5))
+        .map(str::to_lowercase)
+}
+
+/// GET /hosted/<md5>.ddreplay while the LocalHttp store is in use
+pub fn routes() -> impl warp::Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
+    use warp::Filter;
+
+    warp::path!("hosted" / String)
+        .and(warp::get())
+        .and_then(|name: String| async move {
+            let dir = match &crate::config::cfg().replay_store {
+                ReplayStoreConfig::LocalHttp { dir, .. } => hosted_dir(dir),
+                _ => return Err(warp::reject::not_found()),
+            };
+            let md5 = name.strip_suffix(&format!(".{REPLAY_FILE_EXTENSION}")).unwrap_or(&name);
+            if !is_md5(md5) {
+                return Err(warp::reject::not_found());
+            }
+            match tokio::fs::read(dir.join(file_name(&md5.to_lowercase()))).await {
+                Ok(data) => Ok(warp::http::Response::builder()
+                    .header("content-type", "application/octet-stream")
+                    .header("content-disposition", format!("attachment; filename=\"{}\"", file_name(md5)))
+                    .body(data)
+                    .unwrap()),
+                Err(_) => Err(warp::reject::not_found()),
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MD5: &str = "0123456789abcdef0123456789ABCDEF";
+
+    #[test]
+    fn is_md5_takes_32_hex_digits() {
+        assert!(is_md5(MD5));
+        assert!(!is_md5(&MD5[1..]));
+        assert!(!is_md5(&format!("{MD5}0")));
+        assert!(!is_md5("0123456789abcdef0123456789abcdeg"));
+        assert!(!is_md5("../../../../../../etc/passwd...."));
+    }
+
+    #[test]
+    fn md5_of_link_strips_the_store_prefix() {
+        let store = DdReplayStore { link_base: "https://ddstats.live/replay/".to_string() };
+
+        assert_eq!(md5_of_link(&store, MD5), Some(MD5.to_lowercase()));
+        assert_eq!(md5_of_link(&store, &format!("https://ddstats.live/replay/{MD5}")), Some(MD5.to_lowercase()));
+        assert_eq!(md5_of_link(&store, &format!("https://example.com/replay/{MD5}")), None);
+        assert_eq!(md5_of_link(&store, "https://ddstats.live/replay/../hosted"), None);
+    }
+
+    #[test]
+    fn md5_of_link_strips_the_file_extension() {
+        let store = DirectoryStore { dir: PathBuf::from("shared"), link_base: "https://files.example/dd/".to_string() };
+
+        assert_eq!(md5_of_link(&store, &format!("https://files.example/dd/{MD5}.{REPLAY_FILE_EXTENSION}")), Some(MD5.to_lowercase()));
+        assert_eq!(md5_of_link(&store, &format!("https://files.example/dd/{MD5}.zip")), None);
+    }
+}
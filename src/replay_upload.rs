@@ -0,0 +1,328 @@
+//
+//  replay_upload.rs - One queue for every replay upload (F1, automatic, api, cli)
+//
+//  Replays are deduped by md5, against the queue and against everything this
+//  install already uploaded to the configured store (uploaded_replays.txt in the config dir).
+//
+
+use std::collections::{HashSet, VecDeque};
+use std::io::{ErrorKind, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use clipboard::{ClipboardContext, ClipboardProvider};
+use lazy_static::lazy_static;
+use schemars::JsonSchema;
+use serde::Serialize;
+use hyper::StatusCode;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use crate::consts::{REPLAY_UPLOAD_ATTEMPTS, REPLAY_UPLOAD_TIMEOUT, SUBMIT_BACKOFF_MIN, SUBMIT_BACKOFF_MAX};
+use crate::replay_store::HttpStatusError;
+use crate::threads::{AAS, State, Message};
+use crate::websocket_server::WsBroadcast;
+
+lazy_static! {
+    static ref UPLOADED: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+}
+
+#[derive(Serialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadState {
+    Queued,
+    Uploading,
+    Uploaded,
+    /// The server (or this install) already has it, the link works
+    Exists,
+    /// Network error, tried again after a wait
+    Retrying,
+    Failed,
+}
+
+#[derive(Serialize, JsonSchema, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum UploadError {
+    Exists,
+    Network,
+    Rejected,
+}
+
+#[derive(Serialize, JsonSchema, Clone, Debug)]
+pub struct UploadStatus {
+    pub md5: String,
+    pub state: UploadState,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<UploadError>,
+    pub attempt: u32,
+    pub manual: bool,
+    /// Uploads still waiting behind this one
+    pub queued: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<String>,
+}
+
+struct UploadJob {
+    data: Arc<Vec<u8>>,
+    md5: String,
+    manual: bool,
+    attempt: u32,
+    next_attempt: Instant,
+}
+
+pub struct ReplayUploader {
+    queue: VecDeque<UploadJob>,
+}
+
+impl ReplayUploader {
+    pub async fn init(state: AAS<State>) {
+        // The bus is only read here, a slow upload can't make it miss requests
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut bus_recv = state.load().msg_bus.0.subscribe();
+        tokio::spawn(async move {
+            loop {
+                match bus_recv.recv().await {
+                    Ok(Message::UploadReplayData(data, manual)) => {
+                        if tx.send((data, manual)).is_err() {
+                            break;
+                        }
+                    },
+                    Ok(_) => {},
+                    Err(RecvError::Lagged(n)) => log::error!("Replay uploader missed {n} messages"),
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let mut drain_interval = tokio::time::interval(Duration::from_secs(1));
+            let mut c = Self { queue: VecDeque::new() };
+
+            loop {
+                tokio::select! {
+                    request = rx.recv() => match request {
+                        Some((data, manual)) => {
+                            c.enqueue(&state, data, manual);
+                            c.drain(&state).await;
+                        },
+                        None => break,
+                    },
+                    _elapsed = drain_interval.tick() => {
+                        c.drain(&state).await;
+                    }
+                };
+            }
+        });
+    }
+
+    fn enqueue(&mut self, state: &AAS<State>, data: Arc<Vec<u8>>, manual: bool) {
+        let md5 = replay_md5(&data);
+
+        if let Some(job) = self.queue.iter_mut().find(|job| job.md5 == md5) {
+            // Already waiting, a manual request still gets the clipboard link at the end
+            job.manual |= manual;
+            return;
+        }
+
+        let job = UploadJob { data, md5, manual, attempt: 0, next_attempt: Instant::now() };
+        if is_uploaded(&job.md5) {
+            self.finish(state, &job, UploadState::Exists, Some(UploadError::Exists));
+            return;
+        }
+
+        if manual {
+            let _ = state.load().msg_bus.0.send(Message::Log("Uploading Replay...".to_string()));
+        }
+        self.queue.push_back(job);
+        let job = self.queue.back().unwrap();
+        self.report(state, job, UploadState::Queued, None);
+    }
+
+    async fn drain(&mut self, state: &AAS<State>) {
+        // Jobs waiting out a retry don't hold up the ones behind them
+        while let Some(pos) = self.queue.iter().position(|job| Instant::now() >= job.next_attempt) {
+            let mut job = self.queue.remove(pos).unwrap();
+            job.attempt += 1;
+            self.report(state, &job, UploadState::Uploading, None);
+
+            match upload(job.data.clone(), job.manual).await {
+                Ok(()) => {
+                    mark_uploaded(&job.md5);
+                    self.finish(state, &job, UploadState::Uploaded, None);
+                },
+                Err(UploadError::Exists) => {
+                    mark_uploaded(&job.md5);
+                    self.finish(state, &job, UploadState::Exists, Some(UploadError::Exists));
+                },
+                Err(UploadError::Network) if job.attempt < REPLAY_UPLOAD_ATTEMPTS => {
+                    let wait = (SUBMIT_BACKOFF_MIN * 2u32.pow(job.attempt)).min(SUBMIT_BACKOFF_MAX);
+                    log::warn!("Replay upload {} failed, retrying in {:?}", job.md5, wait);
+                    job.next_attempt = Instant::now() + wait;
+                    self.report(state, &job, UploadState::Retrying, Some(UploadError::Network));
+                    self.queue.push_back(job);
+                },
+                Err(error) => self.finish(state, &job, UploadState::Failed, Some(error)),
+            }
+        }
+    }
+
+    fn finish(&self, state: &AAS<State>, job: &UploadJob, result: UploadState, error: Option<UploadError>) {
+        let line = match (result, error) {
+            (UploadState::Uploaded, _) => "Replay Uploaded",
+            (UploadState::Exists, _) => "Replay Exists",
+            (_, Some(UploadError::Network)) => "Replay Upload Offline",
+            _ => "Replay Rejected",
+        };
+        if job.manual || result == UploadState::Uploaded {
+            let _ = state.load().msg_bus.0.send(Message::Log(line.to_string()));
+        }
+
+        let has_link = matches!(result, UploadState::Uploaded | UploadState::Exists);
+        if has_link && job.manual && crate::config::cfg().auto_clipboard {
+            let ctx: Result<ClipboardContext, _> = ClipboardProvider::new();
+            if let Ok(mut ctx) = ctx {
+                let _ = ctx.set_contents(replay_link(&job.md5));
+            }
+        }
+
+        self.report(state, job, result, error);
+    }
+
+    fn report(&self, state: &AAS<State>, job: &UploadJob, upload_state: UploadState, error: Option<UploadError>) {
+        let has_link = matches!(upload_state, UploadState::Uploaded | UploadState::Exists);
+        let status = UploadStatus {
+            md5: job.md5.clone(),
+            state: upload_state,
+            error,
+            attempt: job.attempt,
+            manual: job.manual,
+            queued: self.queue.len(),
+            link: if has_link { Some(replay_link(&job.md5)) } else { None },
+        };
+        let _ = state.load().msg_bus.0.send(Message::WebSocketMessage(WsBroadcast::ReplayUpload(status)));
+    }
+}
+
+pub fn replay_md5(data: &[u8]) -> String {
+    format!("{:x}", ddcore_rs::md5::compute(data))
+}
+
+pub fn replay_link(md5: &str) -> String {
+    crate::replay_store::current().link(md5)
+}
+
+/// A single attempt with the configured store, without the queue (the cli uses this when no instance is running)
+pub async fn upload(data: Arc<Vec<u8>>, manual: bool) -> Result<(), UploadError> {
+    let md5 = replay_md5(&data);
+    match tokio::time::timeout(REPLAY_UPLOAD_TIMEOUT, crate::replay_store::current().upload(data, md5, manual)).await {
+        Ok(res) => res,
+        Err(_) => {
+            log::warn!("Replay upload timed out after {:?}", REPLAY_UPLOAD_TIMEOUT);
+            Err(UploadError::Network)
+        },
+    }
+}
+
+/// What a failed store upload means for the queue, by status code or io error kind
+pub fn classify(e: &anyhow::Error) -> UploadError {
+    for cause in e.chain() {
+        if let Some(e) = cause.downcast_ref::<HttpStatusError>() {
+            return match e.status {
+                StatusCode::CONFLICT => UploadError::Exists,
+                StatusCode::REQUEST_TIMEOUT | StatusCode::TOO_MANY_REQUESTS => UploadError::Network,
+                status if status.is_server_error() => UploadError::Network,
+                _ => UploadError::Rejected,
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return match e.kind() {
+                ErrorKind::AlreadyExists => UploadError::Exists,
+                ErrorKind::InvalidInput | ErrorKind::InvalidData | ErrorKind::PermissionDenied | ErrorKind::Unsupported => UploadError::Rejected,
+                _ => UploadError::Network,
+            };
+        }
+        // Transport errors, the request never got an answer
+        if cause.is::<hyper::Error>() {
+            return UploadError::Network;
+        }
+    }
+    UploadError::Rejected
+}
+
+fn uploaded_file() -> PathBuf {
+    crate::config::get_config_dir().join("uploaded_replays.txt")
+}
+
+fn with_uploaded<T>(f: impl FnOnce(&mut HashSet<String>) -> T) -> T {
+    let mut uploaded = UPLOADED.lock().unwrap();
+    let set = uploaded.get_or_insert_with(|| {
+        std::fs::read_to_string(uploaded_file())
+            .map(|s| s.lines().map(|l| l.trim().to_owned()).filter(|l| !l.is_empty()).collect())
+            .unwrap_or_default()
+    });
+    f(set)
+}
+
+/// Lines are "<store> <md5>", the same replay can still go to another store
+fn dedupe_key(md5: &str) -> String {
+    format!("{} {md5}", crate::replay_store::current().name())
+}
+
+pub fn is_uploaded(md5: &str) -> bool {
+    with_uploaded(|set| set.contains(&dedupe_key(md5)))
+}
+
+fn mark_uploaded(md5: &str) {
+    let key = dedupe_key(md5);
+    if !with_uploaded(|set| set.insert(key.clone())) {
+        return;
+    }
+    let res = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(uploaded_file())
+        .and_then(|mut f| writeln!(f, "{key}"));
+    if let Err(e) = res {
+        log::warn!("Couldn't record uploaded replay {md5}: {e:?}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(status: StatusCode) -> anyhow::Error {
+        HttpStatusError { status, body: String::new() }.into()
+    }
+
+    fn io(kind: ErrorKind) -> anyhow::Error {
+        std::io::Error::from(kind).into()
+    }
+
+    #[test]
+    fn classify_by_status_code() {
+        assert_eq!(classify(&status(StatusCode::CONFLICT)), UploadError::Exists);
+        assert_eq!(classify(&status(StatusCode::REQUEST_TIMEOUT)), UploadError::Network);
+        assert_eq!(classify(&status(StatusCode::TOO_MANY_REQUESTS)), UploadError::Network);
+        assert_eq!(classify(&status(StatusCode::BAD_GATEWAY)), UploadError::Network);
+        assert_eq!(classify(&status(StatusCode::SERVICE_UNAVAILABLE)), UploadError::Network);
+        assert_eq!(classify(&status(StatusCode::BAD_REQUEST)), UploadError::Rejected);
+        assert_eq!(classify(&status(StatusCode::PAYLOAD_TOO_LARGE)), UploadError::Rejected);
+    }
+
+    #[test]
+    fn classify_by_io_kind() {
+        assert_eq!(classify(&io(ErrorKind::AlreadyExists)), UploadError::Exists);
+        assert_eq!(classify(&io(ErrorKind::PermissionDenied)), UploadError::Rejected);
+        assert_eq!(classify(&io(ErrorKind::InvalidInput)), UploadError::Rejected);
+        assert_eq!(classify(&io(ErrorKind::ConnectionRefused)), UploadError::Network);
+        assert_eq!(classify(&io(ErrorKind::TimedOut)), UploadError::Network);
+        assert_eq!(classify(&io(ErrorKind::NotFound)), UploadError::Network);
+    }
+
+    #[test]
+    fn classify_looks_through_context() {
+        let e = io(ErrorKind::PermissionDenied).context("Couldn't store replay");
+        assert_eq!(classify(&e), UploadError::Rejected);
+        assert_eq!(classify(&anyhow::anyhow!("exists")), UploadError::Rejected);
+    }
+}
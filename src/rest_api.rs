@@ -0,0 +1,175 @@
+//
+//  rest_api.rs - Plain HTTP endpoints next to the websocket, for curl and scripts
+//
+//  GET  /state            status of the client and the game
+//  GET  /config           config in use
+//  PUT  /config/styles    replaces ui_conf.theming.styles
+//  PUT  /config/modules   replaces ui_conf.game_data_modules
+//  POST /replay/play      { "path": "..." }
+//  POST /replay/upload    { "path": "..." }
+//  GET  /runs             local run history, newest first (?limit=N)
+//  GET  /replays          replay library, newest first (?q=search)
+//
+//  PUT and POST always need the token, GET only if local_server.open_read_only is off
+//
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use hyper::body::Bytes;
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use warp::http::StatusCode;
+use warp::reply::{Json, WithStatus};
+use warp::{Filter, Reply};
+use crate::config::Styles;
+use crate::replay_recv::StatusReply;
+use crate::server_auth;
+use crate::threads::{AAS, State, Message};
+use crate::ui::modules::GameDataModules;
+use crate::ws_protocol::ErrorCode;
+
+type JsonReply = WithStatus<Json>;
+
+#[derive(serde::Deserialize)]
+struct PathBody {
+    path: String,
+}
+
+pub fn routes(state: AAS<State>) -> impl Filter<Extract = impl Reply, Error = warp::Rejection> + Clone {
+    let with_state = warp::any().map(move || state.clone());
+
+    let get_state = warp::path!("state")
+        .and(warp::get())
+        .and(server_auth::read_access())
+        .and(with_state.clone())
+        .map(|state: AAS<State>| ok(&StatusReply::from_state(&state.load())));
+
+    let get_config = warp::path!("config")
+        .and(warp::get())
+        .and(server_auth::read_access())
+        .map(|| ok(&server_auth::public_config()));
+
+    let put_styles = warp::path!("config" / "styles")
+        .and(warp::put())
+        .and(server_auth::write_access())
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .map(|body: Bytes, state: AAS<State>| match parse::<Styles>(&body) {
+            Ok(styles) => {
+                crate::config::update(|c| c.ui_conf.theming.styles = styles);
+                let _ = state.load().msg_bus.0.send(Message::SaveCfg);
+                ok(&crate::config::cfg().ui_conf.theming.styles)
+            },
+            Err(e) => e,
+        });
+
+    let put_modules = warp::path!("config" / "modules")
+        .and(warp::put())
+        .and(server_auth::write_access())
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .map(|body: Bytes, state: AAS<State>| match parse::<Vec<GameDataModules>>(&body) {
+            Ok(modules) => {
+                crate::config::update(|c| c.ui_conf.game_data_modules = modules);
+                let _ = state.load().msg_bus.0.send(Message::SaveCfg);
+                ok(&crate::config::cfg().ui_conf.game_data_modules)
+            },
+            Err(e) => e,
+        });
+
+    let play_replay = warp::path!("replay" / "play")
+        .and(warp::post())
+        .and(server_auth::write_access())
+        .and(warp::body::bytes())
+        .and(with_state.clone())
+        .and_then(|body: Bytes, state: AAS<State>| blocking(move || match parse::<PathBody>(&body) {
+            Ok(body) => {
+                if !std::path::Path::new(&body.path).is_file() {
+                    return error(StatusCode::NOT_FOUND, ErrorCode::BadRequest, format!("No such file: {}", body.path));
+                }
+                let _ = state.load().msg_bus.0.send(Message::PlayReplayLocalFile(body.path));
+                reply(&json!({ "accepted": true }), StatusCode::ACCEPTED)
+            },
+            Err(e) => e,
+        }));
+
+    let upload_replay = warp::path!("replay" / "upload")
+        .and(warp::post())
+        .and(server_auth::write_access())
+        .and(warp::body::bytes())
+        .and(with_state)
+        .and_then(|body: Bytes, state: AAS<State>| blocking(move || match parse::<PathBody>(&body) {
+            Ok(body) => match crate::client::get_replay_file_content(body.path) {
+                Ok(replay) => {
+                    let _ = state.load().msg_bus.0.send(Message::UploadReplayData(Arc::new(replay), true));
+                    reply(&json!({ "accepted": true }), StatusCode::ACCEPTED)
+                },
+                Err(e) => error(StatusCode::UNPROCESSABLE_ENTITY, ErrorCode::Failed, format!("{e}")),
+            },
+            Err(e) => e,
+        }));
+
+    let runs = warp::path!("runs")
+        .and(warp::get())
+        .and(server_auth::read_access())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|query: HashMap<String, String>| blocking(move || {
+            let mut runs = crate::run_history::list_runs();
+            if let Some(limit) = query.get("limit").and_then(|l| l.parse().ok()) {
+                runs.truncate(limit);
+            }
+            ok(&runs)
+        }));
+
+    let replays = warp::path!("replays")
+        .and(warp::get())
+        .and(server_auth::read_access())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(|query: HashMap<String, String>| blocking(move || {
+            ok(&crate::replay_library::search(query.get("q").map_or("", String::as_str)))
+        }));
+
+    get_state
+        .or(get_config)
+        .or(put_styles)
+        .or(put_modules)
+        .or(play_replay)
+        .or(upload_replay)
+        .or(runs)
+        .or(replays)
+        .recover(recover)
+}
+
+fn reply<T: serde::Serialize>(value: &T, status: StatusCode) -> JsonReply {
+    warp::reply::with_status(warp::reply::json(value), status)
+}
+
+fn ok<T: serde::Serialize>(value: &T) -> JsonReply {
+    reply(value, StatusCode::OK)
+}
+
+fn error(status: StatusCode, code: ErrorCode, message: String) -> JsonReply {
+    reply(&json!({ "error": { "code": code, "message": message } }), status)
+}
+
+/// For routes that read or scan files, so they don't stall the server's async workers
+async fn blocking(f: impl FnOnce() -> JsonReply + Send + 'static) -> Result<JsonReply, Infallible> {
+    Ok(tokio::task::spawn_blocking(f).await
+        .unwrap_or_else(|e| error(StatusCode::INTERNAL_SERVER_ERROR, ErrorCode::Failed, format!("{e}"))))
+}
+
+fn parse<T: DeserializeOwned>(body: &Bytes) -> Result<T, JsonReply> {
+    serde_json::from_slice(body).map_err(|e| error(StatusCode::BAD_REQUEST, ErrorCode::BadRequest, format!("{e}")))
+}
+
+/// Wrong method on a known path gets a JSON 405, anything else falls through to the other routes
+async fn recover(rejection: warp::Rejection) -> Result<JsonReply, warp::Rejection> {
+    if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        return Ok(error(StatusCode::METHOD_NOT_ALLOWED, ErrorCode::BadRequest, "Method not allowed".to_string()));
+    }
+    if rejection.find::<warp::reject::PayloadTooLarge>().is_some() {
+        return Ok(error(StatusCode::PAYLOAD_TOO_LARGE, ErrorCode::BadRequest, "Body too large".to_string()));
+    }
+    Err(rejection)
+}
@@ -0,0 +1,268 @@
+//
+//  run_history.rs - Local run history
+//
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use ddcore_rs::models::{StatsBlockWithFrames, StatsFrame};
+use lazy_static::lazy_static;
+use prost::Message as ProstMessage;
+use tokio::sync::broadcast::error::RecvError;
+use crate::client::SubmitGameEvent;
+use crate::grpc_models::{StoredRun, StoredRunFrames, SubmitGameRequest};
+use crate::threads::{AAS, State, Message};
+
+const RUN_FILE_EXTENSION: &str = "ddrun";
+
+lazy_static! {
+    /// Summary of every run file by path and modified time, decoding a run means its replay and every frame
+    static ref SUMMARIES: Mutex<HashMap<PathBuf, (SystemTime, RunSummary)>> = Mutex::new(HashMap::new());
+}
+
+pub struct RunHistory;
+
+impl RunHistory {
+    pub async fn init(state: AAS<State>) {
+        tokio::task::spawn_blocking(crate::personal_best::load_index);
+
+        tokio::spawn(async move {
+            let mut bus_recv = state.load().msg_bus.0.subscribe();
+
+            loop {
+                tokio::select! {
+                    msg = bus_recv.recv() => {
+                        let sge = match msg {
+                            Ok(Message::SubmitGame(sge)) => sge,
+                            Ok(_) => continue,
+                            Err(RecvError::Lagged(n)) => {
+                                log::error!("Run history missed {n} messages, runs in them aren't saved");
+                                continue;
+                            },
+                            Err(RecvError::Closed) => break,
+                        };
+                        if !crate::config::cfg().save_run_history { continue; }
+                        match save_run(&sge) {
+                            Ok(path) => {
+                                log::info!("Saved run to history: {path:?}");
+                                if should_copy_replay(&sge) {
+                                    match crate::replay_library::save_replay(&sge.2) {
+                                        Ok(path) => log::info!("Saved replay to library: {path:?}"),
+                                        Err(e) => log::warn!("Failed to save replay to library: {e:?}"),
+                                    }
+                                }
+                            },
+                            Err(e) => {
+                                log::error!("Failed to save run to history: {e:?}");
+                                let _ = state.load().msg_bus.0.send(Message::Log("History Save Fail".to_string()));
+                            }
+                        }
+                    },
+                };
+            }
+        });
+    }
+}
+
+/// Copied even when the auto save has it too, retention only ever deletes from the auto save folder
+fn should_copy_replay(sge: &SubmitGameEvent) -> bool {
+    crate::config::cfg().replay_library.save_history_replays && !sge.2.is_empty()
+}
+
+#[derive(Debug, Clone, serde::Serialize)]
+pub struct RunSummary {
+    pub snowflake: u64,
+    pub saved_at: u64,
+    pub player_id: i32,
+    pub player_name: String,
+    pub level_hash_md5: String,
+    pub time_max: f32,
+    pub starting_time: f32,
+    pub death_type: i32,
+    pub is_replay: bool,
+    pub gems_collected: i32,
+    pub enemies_killed: i32,
+    pub homing_daggers_max: i32,
+    pub has_replay: bool,
+}
+
+impl RunSummary {
+    pub fn from_stored(run: &StoredRun) -> Self {
+        let submit = run.run.clone().unwrap_or_default();
+        Self {
+            snowflake: run.snowflake,
+            saved_at: run.saved_at,
+            player_id: submit.player_id,
+            player_name: submit.player_name,
+            level_hash_md5: submit.level_hash_md5,
+            time_max: submit.time_max,
+            starting_time: run.starting_time,
+            death_type: submit.death_type,
+            is_replay: submit.is_replay,
+            gems_collected: submit.gems_collected,
+            enemies_killed: submit.enemies_killed,
+            homing_daggers_max: submit.homing_daggers_max,
+            has_replay: !run.replay.is_empty(),
+        }
+    }
+}
+
+impl StoredRun {
+    pub fn from_submit_event(ev: &SubmitGameEvent) -> Self {
+        Self {
+            snowflake: ev.1 as u64,
+            starting_time: ev.0.starting_time,
+            saved_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            run: Some(SubmitGameRequest::from_compiled_run(ev.0.clone())),
+            replay: (*ev.2).clone(),
+        }
+    }
+
+    pub fn game_frames(&self) -> Vec<StatsFrame> {
+        match &self.run {
+            Some(run) => run.frames.iter().map(|f| f.to_game_frame()).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Rebuilds enough of a poll result for the frame helpers (`get_frame_for_time` etc.)
+    pub fn to_stats_block_with_frames(&self) -> StatsBlockWithFrames {
+        stats_block_with_frames(self.starting_time, self.run.as_ref())
+    }
+}
+
+impl StoredRunFrames {
+    pub fn to_stats_block_with_frames(&self) -> StatsBlockWithFrames {
+        stats_block_with_frames(self.starting_time, self.run.as_ref())
+    }
+}
+
+fn stats_block_with_frames(starting_time: f32, run: Option<&SubmitGameRequest>) -> StatsBlockWithFrames {
+    let mut data = StatsBlockWithFrames {
+        frames: run.map_or_else(Vec::new, |run| run.frames.iter().map(|f| f.to_game_frame()).collect()),
+        ..Default::default()
+    };
+    data.block.starting_time = starting_time;
+    if let Some(run) = run {
+        data.block.time_max = run.time_max;
+        data.block.time_lvl2 = run.time_lvl2;
+        data.block.time_lvl3 = run.time_lvl3;
+        data.block.time_lvl4 = run.time_lvl4;
+        data.block.levi_down_time = run.time_levi_down;
+        data.block.orb_down_time = run.time_orb_down;
+        data.block.max_homing = run.homing_daggers_max;
+        data.block.time_max_homing = run.homing_daggers_max_time;
+        data.block.is_replay = run.is_replay;
+    }
+    data
+}
+
+pub fn get_history_dir() -> PathBuf {
+    crate::config::get_config_dir().join("history")
+}
+
+fn run_file_path(snowflake: u64) -> PathBuf {
+    get_history_dir().join(format!("{snowflake}.{RUN_FILE_EXTENSION}"))
+}
+
+pub fn save_run(ev: &SubmitGameEvent) -> anyhow::Result<PathBuf> {
+    let stored = StoredRun::from_submit_event(ev);
+    std::fs::create_dir_all(get_history_dir())?;
+
+    // Write to a temp file first so a crash never leaves a half written run behind
+    let path = run_file_path(stored.snowflake);
+    let tmp_path = path.with_extension("tmp");
+    let mut f = File::create(&tmp_path)?;
+    f.write_all(&stored.encode_to_vec())?;
+    f.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+    crate::personal_best::add_run(&stored);
+
+    Ok(path)
+}
+
+pub fn load_run(snowflake: u64) -> anyhow::Result<StoredRun> {
+    load_run_file(&run_file_path(snowflake))
+}
+
+fn load_run_file(path: &Path) -> anyhow::Result<StoredRun> {
+    let mut f = File::open(path)?;
+    let mut buf = Vec::new();
+    f.read_to_end(&mut buf)?;
+    Ok(StoredRun::decode(&buf[..])?)
+}
+
+/// Every stored run, newest first
+pub fn load_all_runs() -> Vec<StoredRun> {
+    let mut runs: Vec<StoredRun> = run_files()
+        .iter()
+        .filter_map(|path| match load_run_file(path) {
+            Ok(run) => Some(run),
+            Err(e) => {
+                log::warn!("Skipping unreadable history file {path:?}: {e:?}");
+                None
+            }
+        })
+        .collect();
+    runs.sort_by(|a, b| b.snowflake.cmp(&a.snowflake));
+    runs
+}
+
+/// Every stored run without its replay, for code that only needs the frames
+pub fn load_all_run_frames() -> Vec<StoredRunFrames> {
+    run_files()
+        .iter()
+        .filter_map(|path| match load_run_frames_file(path) {
+            Ok(run) => Some(run),
+            Err(e) => {
+                log::warn!("Skipping unreadable history file {path:?}: {e:?}");
+                None
+            }
+        })
+        .collect()
+}
+
+fn load_run_frames_file(path: &Path) -> anyhow::Result<StoredRunFrames> {
+    let buf = std::fs::read(path)?;
+    Ok(StoredRunFrames::decode(&buf[..])?)
+}
+
+/// Summaries of every stored run, newest first. Only new or changed files are decoded
+pub fn list_runs() -> Vec<RunSummary> {
+    let files = run_files();
+    let mut index = SUMMARIES.lock().unwrap();
+    index.retain(|path, _| files.contains(path));
+
+    for path in files {
+        let modified = match std::fs::metadata(&path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        if matches!(index.get(&path), Some((indexed, _)) if *indexed == modified) {
+            continue;
+        }
+        match load_run_file(&path) {
+            Ok(run) => { index.insert(path, (modified, RunSummary::from_stored(&run))); },
+            Err(e) => log::warn!("Skipping unreadable history file {path:?}: {e:?}"),
+        }
+    }
+
+    let mut runs: Vec<RunSummary> = index.values().map(|(_, summary)| summary.clone()).collect();
+    runs.sort_by(|a, b| b.snowflake.cmp(&a.snowflake));
+    runs
+}
+
+fn run_files() -> Vec<PathBuf> {
+    let dir = match std::fs::read_dir(get_history_dir()) {
+        Ok(dir) => dir,
+        Err(_) => return vec![],
+    };
+
+    dir.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == RUN_FILE_EXTENSION))
+        .collect()
+}
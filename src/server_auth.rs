@@ -0,0 +1,148 @@
+//
+//  server_auth.rs - Origin allowlist and token for the local http/websocket server
+//
+//  The token can be sent as "Authorization: Bearer <token>", "X-DDStats-Token: <token>"
+//  or "?token=<token>" (browsers can't set headers on websockets and EventSource)
+//
+//  Clients from other machines (local_server.allow_lan) always need the token, even to read
+//
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+use crate::ws_protocol::ErrorCode;
+
+#[derive(Debug)]
+pub struct ForbiddenOrigin;
+impl warp::reject::Reject for ForbiddenOrigin {}
+
+#[derive(Debug)]
+pub struct Unauthorized;
+impl warp::reject::Reject for Unauthorized {}
+
+/// What a request is allowed to do
+#[derive(Clone, Copy, Debug)]
+pub struct Access {
+    pub authorized: bool,
+    pub remote: bool,
+}
+
+impl Access {
+    pub fn can_read(&self) -> bool {
+        self.authorized || (!self.remote && crate::config::cfg().local_server.open_read_only)
+    }
+
+    pub fn can_write(&self) -> bool {
+        self.authorized
+    }
+}
+
+/// Creates the per install token the first time, returns true if the config changed
+pub fn ensure_token() -> bool {
+    if !crate::config::cfg().local_server.token.is_empty() {
+        return false;
+    }
+    let token = generate_token();
+    crate::config::update(|c| c.local_server.token = token);
+    true
+}
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 16];
+    getrandom::getrandom(&mut bytes).expect("No OS random source for the server token");
+    bytes.iter().fold(String::with_capacity(32), |mut hex, b| {
+        let _ = write!(hex, "{b:02x}");
+        hex
+    })
+}
+
+/// The config as served to clients, the token stays out of it
+pub fn public_config() -> serde_json::Value {
+    let mut value = serde_json::to_value(crate::config::cfg().as_ref()).unwrap();
+    if let Some(token) = value.pointer_mut("/local_server/token") {
+        *token = serde_json::Value::String(String::new());
+    }
+    value
+}
+
+pub fn is_valid_token(token: &str) -> bool {
+    let cfg = crate::config::cfg();
+    let expected = cfg.local_server.token.as_bytes();
+    let given = token.as_bytes();
+    if expected.is_empty() || expected.len() != given.len() {
+        return false;
+    }
+    // Same time whatever the first wrong byte is
+    expected.iter().zip(given).fold(0u8, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+/// Requests without an Origin header don't come from a web page (curl, OBS, scripts)
+pub fn origin_allowed(origin: Option<&str>) -> bool {
+    match origin {
+        None => true,
+        Some(origin) => crate::config::cfg().local_server.allowed_origins.iter()
+            .any(|allowed| allowed == "*" || allowed.trim_end_matches('/').eq_ignore_ascii_case(origin)),
+    }
+}
+
+/// Rejects pages that aren't on the allowlist before any route runs
+pub fn check_origin() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and_then(|origin: Option<String>| async move {
+            if origin_allowed(origin.as_deref()) {
+                Ok(())
+            } else {
+                log::warn!("Rejected request from origin {:?}", origin);
+                Err(warp::reject::custom(ForbiddenOrigin))
+            }
+        })
+        .untuple_one()
+}
+
+/// Whether the request carries the token, in a header or the query string
+pub fn access() -> impl Filter<Extract = (Access,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::header::optional::<String>("x-ddstats-token"))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::addr::remote())
+        .map(|authorization: Option<String>, header: Option<String>, query: HashMap<String, String>, peer: Option<SocketAddr>| {
+            let bearer = authorization.as_deref().and_then(|a| a.strip_prefix("Bearer ")).map(|t| t.trim().to_owned());
+            let token = bearer.or(header).or_else(|| query.get("token").cloned());
+            Access {
+                authorized: token.map_or(false, |t| is_valid_token(&t)),
+                remote: peer.map_or(true, |p| !p.ip().is_loopback()),
+            }
+        })
+}
+
+pub fn read_access() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    access()
+        .and_then(|access: Access| async move {
+            if access.can_read() { Ok(()) } else { Err(warp::reject::custom(Unauthorized)) }
+        })
+        .untuple_one()
+}
+
+pub fn write_access() -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    access()
+        .and_then(|access: Access| async move {
+            if access.can_write() { Ok(()) } else { Err(warp::reject::custom(Unauthorized)) }
+        })
+        .untuple_one()
+}
+
+/// Turns our rejections into JSON errors, everything else is left to warp
+pub async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Rejection> {
+    let (status, code, message) = if rejection.find::<ForbiddenOrigin>().is_some() {
+        (StatusCode::FORBIDDEN, ErrorCode::Unauthorized, "Origin not allowed")
+    } else if rejection.find::<Unauthorized>().is_some() {
+        (StatusCode::UNAUTHORIZED, ErrorCode::Unauthorized, "Missing or wrong token")
+    } else {
+        return Err(rejection);
+    };
+
+    let body = serde_json::json!({ "error": { "code": code, "message": message } });
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
@@ -0,0 +1,92 @@
+//
+//  submit_queue.rs - Durable outbox for game submissions
+//
+
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use prost::Message as ProstMessage;
+use crate::client::SubmitGameEvent;
+use crate::grpc_models::StoredRun;
+
+const QUEUE_FILE_EXTENSION: &str = "pending";
+
+pub fn get_queue_dir() -> PathBuf {
+    crate::config::get_config_dir().join("outbox")
+}
+
+fn entry_path(snowflake: u64) -> PathBuf {
+    get_queue_dir().join(format!("{snowflake}.{QUEUE_FILE_EXTENSION}"))
+}
+
+pub fn enqueue(ev: &SubmitGameEvent) -> anyhow::Result<u64> {
+    let entry = StoredRun::from_submit_event(ev);
+    std::fs::create_dir_all(get_queue_dir())?;
+
+    let path = entry_path(entry.snowflake);
+    let tmp_path = path.with_extension("tmp");
+    let mut f = File::create(&tmp_path)?;
+    f.write_all(&entry.encode_to_vec())?;
+    f.sync_all()?;
+    std::fs::rename(&tmp_path, &path)?;
+
+    Ok(entry.snowflake)
+}
+
+pub fn remove(snowflake: u64) -> anyhow::Result<()> {
+    std::fs::remove_file(entry_path(snowflake))?;
+    Ok(())
+}
+
+/// Runs the server kept failing on, kept for a manual look instead of blocking the queue
+pub fn get_failed_dir() -> PathBuf {
+    get_queue_dir().join("failed")
+}
+
+pub fn set_aside(snowflake: u64) -> anyhow::Result<()> {
+    std::fs::create_dir_all(get_failed_dir())?;
+    std::fs::rename(entry_path(snowflake), get_failed_dir().join(format!("{snowflake}.{QUEUE_FILE_EXTENSION}")))?;
+    Ok(())
+}
+
+/// Oldest queued submission, the queue drains in the order runs were played
+pub fn peek() -> Option<StoredRun> {
+    let mut files = queue_files();
+    files.sort();
+
+    for path in files {
+        let buf = match std::fs::read(&path) {
+            Ok(buf) => buf,
+            Err(e) => {
+                // Locked or gone for now (antivirus, another instance), the next drain tries again
+                log::warn!("Skipping outbox entry {path:?}: {e:?}");
+                continue;
+            },
+        };
+        match StoredRun::decode(&buf[..]) {
+            Ok(entry) => return Some(entry),
+            Err(e) => {
+                log::warn!("Dropping corrupt outbox entry {path:?}: {e:?}");
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+    }
+
+    None
+}
+
+pub fn depth() -> usize {
+    queue_files().len()
+}
+
+fn queue_files() -> Vec<PathBuf> {
+    let dir = match std::fs::read_dir(get_queue_dir()) {
+        Ok(dir) => dir,
+        Err(_) => return vec![],
+    };
+
+    dir.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == QUEUE_FILE_EXTENSION))
+        .collect()
+}
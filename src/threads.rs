@@ -2,12 +2,12 @@
 //  threads.rs - Management of threads 
 //  Rewrite Counter: 3 x (I HATE WINDOWS)
 
-use crate::{client::{ConnectionState, GamePollClient, SubmitGameEvent}, grpc_client::GameSubmissionClient, socketio_client::LiveGameClient, ui::UiThread, websocket_server::{WebsocketServer, WsBroadcast}, discord::RichPresenceClient, replay_recv::LocalReplayReceiver};
-use std::{sync::Arc, time::UNIX_EPOCH, net::TcpListener};
+use crate::{client::{ConnectionState, GamePollClient, SubmitGameEvent}, grpc_client::GameSubmissionClient, socketio_client::LiveGameClient, ui::UiThread, websocket_server::{WebsocketServer, WsBroadcast}, discord::RichPresenceClient, replay_recv::LocalReplayReceiver, run_history::RunHistory, headless::HeadlessLogThread, config_watcher::ConfigWatcher, livesplit::LiveSplitClient, replay_upload::ReplayUploader};
+use std::{sync::Arc, time::UNIX_EPOCH, net::{SocketAddr, TcpListener}};
 use arc_swap::ArcSwap;
-use clap::Arg;
 use ddcore_rs::models::StatsBlockWithFrames;
 use crate::socketio_client::SubmitSioEvent;
+use crate::cli::CliCommand;
 
 pub type AAS<T> = Arc<ArcSwap<T>>;
 
@@ -50,35 +50,49 @@ pub async fn init() {
         msg_bus
     }));
 
-    GamePollClient::init(state.clone()).await;
-    UiThread::init(state.clone()).await;
-    #[cfg(target_os = "windows")] crate::tray::TrayIcon::init(state.clone()).await;
-    #[cfg(target_os = "windows")] let _ = winconsole::console::set_title("ddstats-rust");
-
-    let app = clap::App::new("ddstats-rust")
-        .bin_name("ddstats-rust")
-        .arg(Arg::new("replay")
-            .takes_value(true)
-            .value_name("FILE")
-            .required(false)
-            .help("Opens replay with ddstats-rust"));
+    let (replay, headless) = match crate::cli::parse() {
+        CliCommand::Run { replay, headless } => (replay, headless || cfg.headless),
+        cmd => {
+            if let Err(e) = crate::cli::run_oneshot(cmd).await {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            return;
+        }
+    };
 
     let mut repl = None;
-    if let Some(replay) = app.get_matches().value_of("replay") {
-        if !port_is_available(18639) {
-            crate::replay_recv::send_to_current_instance(replay.to_owned()).await;
+    if let Some(replay) = replay {
+        if !control_port_is_available() {
+            match crate::replay_recv::send_to_current_instance(replay).await {
+                Ok(_) => println!("Replay sent to the running instance"),
+                Err(e) => eprintln!("Couldn't send replay to the running instance: {e}"),
+            }
             return;
         } else {
-            repl = Some(replay.to_owned());
+            repl = Some(replay);
         }
     }
 
-    if !port_is_available(18639) {
+    GamePollClient::init(state.clone()).await;
+    if headless {
+        HeadlessLogThread::init(state.clone()).await;
+    } else {
+        UiThread::init(state.clone()).await;
+        #[cfg(target_os = "windows")] crate::tray::TrayIcon::init(state.clone()).await;
+        #[cfg(target_os = "windows")] let _ = winconsole::console::set_title("ddstats-rust");
+    }
+
+    if !control_port_is_available() {
         log::error!("local replay port already bound, ddstats-rust is probably already open.");
         return;
     }
 
     LocalReplayReceiver::init(state.clone()).await;
+    RunHistory::init(state.clone()).await;
+    ReplayUploader::init(state.clone()).await;
+    ConfigWatcher::init(state.clone()).await;
+    LiveSplitClient::init(state.clone()).await;
 
     if !cfg.offline {
         log::info!("ONLINE MODE!");
@@ -89,6 +103,11 @@ pub async fn init() {
     }
 
     let mut bus_recv = state.load().msg_bus.0.subscribe();
+
+    for line in crate::config::LOAD_REPORT.load().lines() {
+        log::info!("{line}");
+        let _ = state.load().msg_bus.0.send(Message::Log(line));
+    }
     
     if let Some(repl) = repl {
         let _ = state.load().msg_bus.0.send(Message::PlayReplayLocalFile(repl));
@@ -122,10 +141,18 @@ pub async fn init() {
                 },
                 _ => {}
             },
+            _ = tokio::signal::ctrl_c(), if headless => {
+                let _ = state.load().msg_bus.0.send(Message::Exit);
+            },
         };
     }
 }
 
-pub fn port_is_available(port: u16) -> bool {
-    TcpListener::bind(("127.0.0.1", port)).is_ok()
+pub fn port_is_available(addr: SocketAddr) -> bool {
+    TcpListener::bind(addr).is_ok()
+}
+
+/// False when another instance already holds the configured control port
+pub fn control_port_is_available() -> bool {
+    port_is_available(crate::config::cfg().local_server.control_addr())
 }
@@ -18,15 +18,17 @@ use crossterm::{
     terminal::{disable_raw_mode, enable_raw_mode, LeaveAlternateScreen},
 };
 use crate::{client::ConnectionState, config::{self, LogoStyle}, consts::*, threads::{AAS, State, Message}};
+use crate::reference_run::{self, ReferenceSource};
 
-use self::{orb_animation::LeviRipple, ascii_canvas::AsciiCanvas};
+use self::{orb_animation::LeviRipple, ascii_canvas::AsciiCanvas, replay_browser::{ReplayBrowser, BrowserAction}};
 
 // Re-exports
 pub mod orb_animation;
 pub mod ascii_canvas;
 pub mod modules;
+pub mod replay_browser;
 
-#[derive(Deserialize, Clone, serde::Serialize)]
+#[derive(Deserialize, Clone, serde::Serialize, schemars::JsonSchema)]
 pub enum SizeStyle {
     Minimal,
     Compact,
@@ -42,6 +44,7 @@ pub struct UiThread;
 #[derive(Clone, Debug)]
 pub struct ExtraSettings {
     homing_always_visible: bool,
+    ghost_visible: bool,
     draw_ui: bool,
     help: bool,
 }
@@ -52,9 +55,11 @@ impl UiThread {
         term.clear().expect("Couldn't clear terminal");
         let mut interval = tokio::time::interval(Duration::from_secs_f32(1. / 12.));
         let mut log_list = vec![];
+        let mut replay_browser = ReplayBrowser::default();
         tokio::spawn(async move {
             let mut extra_settings = ExtraSettings {
                 homing_always_visible: crate::config::cfg().ui_conf.always_show_splits,
+                ghost_visible: true,
                 draw_ui: crate::config::cfg().ui_conf.enabled,
                 help: false
             };
@@ -103,6 +108,20 @@ impl UiThread {
                         if ev_res.is_ok() {
                             let ev = ev_res.unwrap();
                             match ev {
+                                // The replay screen takes every key while it's open, typing is the search
+                                Event::Input(event) if replay_browser.open => {
+                                    match replay_browser.handle_key(event.code) {
+                                        Some(BrowserAction::Play(path)) => {
+                                            let _ = state.msg_bus.0.send(Message::PlayReplayLocalFile(path));
+                                            replay_browser.open = false;
+                                        },
+                                        Some(BrowserAction::Reference(file_name)) => {
+                                            select_reference(&state, ReferenceSource::Replay(file_name));
+                                            replay_browser.open = false;
+                                        },
+                                        None => {},
+                                    }
+                                },
                                 Event::Input(event) => match event.code {
                                     KeyCode::Char('q') => {
                                         disable_raw_mode().expect("I can't");
@@ -123,14 +142,36 @@ impl UiThread {
                                     KeyCode::F(5) => {
                                         extra_settings.homing_always_visible = !extra_settings.homing_always_visible;
                                     },
+                                    KeyCode::F(6) => {
+                                        match config::select_split_profile(None) {
+                                            Ok(name) => {
+                                                let _ = state.msg_bus.0.send(Message::Log(format!("Splits: {name}")));
+                                                let _ = state.msg_bus.0.send(Message::SaveCfg);
+                                            },
+                                            Err(e) => { let _ = state.msg_bus.0.send(Message::Log(format!("{e}"))); },
+                                        }
+                                    },
                                     KeyCode::F(4) => {
                                         extra_settings.help= !extra_settings.help;
                                     },
+                                    KeyCode::F(7) => {
+                                        replay_browser.toggle();
+                                    },
+                                    KeyCode::F(8) => {
+                                        if reference_run::current().is_some() {
+                                            reference_run::clear();
+                                            let _ = state.msg_bus.0.send(Message::Log("Comparison cleared".to_string()));
+                                        } else {
+                                            select_reference(&state, ReferenceSource::Best);
+                                        }
+                                    },
+                                    KeyCode::F(9) => {
+                                        extra_settings.ghost_visible = !extra_settings.ghost_visible;
+                                    },
                                     KeyCode::F(2) => {
                                         let _ = state.msg_bus.0.send(Message::HideWindow);
                                     },
                                     KeyCode::F(1) => {
-                                        let _ = state.msg_bus.0.send(Message::Log("Uploading Replay...".to_string()));
                                         let _ = state.msg_bus.0.send(Message::UploadReplayBuffer);
                                     },
                                     _ => {}
@@ -212,7 +253,9 @@ impl UiThread {
                                 crate::ui::draw_logs(f, info[0], &log_list);
                             }
         
-                            if extra_settings.help {
+                            if replay_browser.open {
+                                replay_browser::draw_replay_browser(f, info[info.len() - 1], &replay_browser);
+                            } else if extra_settings.help {
                                 crate::ui::draw_help_screen(
                                     f,
                                     info[info.len() - 1],
@@ -246,6 +289,19 @@ impl UiThread {
     }
 }
 
+/// Loading a reference reads the whole run history, the result shows up in the logs
+fn select_reference(state: &State, source: ReferenceSource) {
+    let level_hash = state.last_poll.block.level_hash();
+    let log_sender = state.msg_bus.0.clone();
+    tokio::task::spawn_blocking(move || {
+        let line = match reference_run::select(&source, &level_hash) {
+            Ok(label) => format!("Comparing against {label}"),
+            Err(e) => format!("{e}"),
+        };
+        let _ = log_sender.send(Message::Log(line));
+    });
+}
+
 pub fn create_term() -> Terminal<CrosstermBackend<Stdout>> {
     enable_raw_mode().expect("Couldn't set terminal to raw mode");
 
@@ -324,10 +380,15 @@ pub fn draw_info_table<B>(
         Constraint::Max(10),
     ];
 
+    let title = match config::active_split_profile(&cfg) {
+        Some(profile) => format!("Game Data [{}]", profile.name),
+        None => "Game Data".to_string(),
+    };
+
     let t = Table::new(rows)
         .block(Block::default().borders(Borders::empty()))
         .widths(&widths)
-        .block(Block::default().borders(Borders::ALL).title(Span::styled("Game Data", cfg.ui_conf.theming.styles.game_data_title)))
+        .block(Block::default().borders(Borders::ALL).title(Span::styled(title, cfg.ui_conf.theming.styles.game_data_title)))
         .style(cfg.ui_conf.theming.styles.game_data)
         .column_spacing(1);
     f.render_widget(t, area);
@@ -349,10 +410,20 @@ pub fn draw_help_screen<B>(
     rows.push(Row::new(vec!["   Toggle UI Rendering", "F3"]));
     rows.push(Row::new(vec!["   Toggle Help Screen", "F4"]));
     rows.push(Row::new(vec!["   Show All Splits", "F5"]));
+    rows.push(Row::new(vec!["   Next Split Profile", "F6"]));
+    rows.push(Row::new(vec!["   Replay Library", "F7"]));
+    rows.push(Row::new(vec!["   Compare Replay", "Tab in F7"]));
+    rows.push(Row::new(vec!["   Compare Best Run", "F8"]));
+    rows.push(Row::new(vec!["   Toggle Ghost", "F9"]));
     rows.push(Row::new(vec!["   Quit Safely", "q"]));
 
     rows.push(Row::new(vec!["", ""]));
 
+    let token = if cfg.local_server.token.is_empty() { "-".to_string() } else { cfg.local_server.token.clone() };
+    rows.push(Row::new(vec!["   Local API Token".to_string(), token]));
+
+    rows.push(Row::new(vec!["", ""]));
+
     rows.push(Row::new(vec!["   ddstats-go and ddstats website", "VHS (github.com/alexwilkerson)"]));
     rows.push(Row::new(vec!["   Programming / Replay Server", "KyoZM (github.com/lsaa)"]));
     rows.push(Row::new(vec!["   DDInfo API / DDCL", "xvlv (github.com/NoahStolk)"]));
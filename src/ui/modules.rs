@@ -6,9 +6,13 @@ use ddcore_rs::models::{StatsBlockWithFrames, GameStatus, StatsFrame};
 use num_traits::FromPrimitive;
 use tui::{widgets::Row, style::Modifier, text::{Span, Spans}};
 use crate::config;
+use crate::personal_best::{PbComparison, SplitReference};
 use super::{ExtraSettings, SizeStyle};
 
-#[derive(serde::Deserialize, serde::Serialize, Clone)]
+/// (Name, Time, Offset, Positive threshold, Neutral zone, Golden Split)
+pub type Split = (String, f32, i32, i32, u32, Option<i32>);
+
+#[derive(serde::Deserialize, serde::Serialize, schemars::JsonSchema, Clone)]
 pub enum GameDataModules {
     RunData,
     Timer,
@@ -18,10 +22,14 @@ pub enum GameDataModules {
     Accuracy,
     GemsLost(SizeStyle), // Minimal, Compact, Full
     CollectionAccuracy,
-    HomingSplits(Vec<(String, f32, i32, i32, u32, Option<i32>)>), // (Name, Time, Offset, Positive threshold, Neutral zone, Golden Split)
+    HomingSplits(Vec<Split>), // (Name, Time, Offset, Positive threshold, Neutral zone, Golden Split)
+    ProfileSplits, // HomingSplits from the active split profile
+    PbSplits(PbComparison), // Active split profile compared to your run history: BestRun, SumOfBest
     HomingUsed,
     DaggersEaten,
     FarmEfficiency,
+    Comparison(usize), // Per second deltas against the reference run ( rows )
+    Ghost, // The reference run at the current time
     Spacing,
 }
 
@@ -37,10 +45,14 @@ impl<'a> GameDataModules {
             GameDataModules::Accuracy => create_accuracy_rows(data),
             GameDataModules::GemsLost(size_style) => create_gems_lost_rows(data, size_style.clone()),
             GameDataModules::CollectionAccuracy => create_collection_accuracy_rows(data),
-            GameDataModules::HomingSplits(times) => create_homing_splits_rows(data, times.clone(), extra.clone()),
+            GameDataModules::HomingSplits(times) => create_homing_splits_rows(data, times.clone(), None, extra.clone()),
+            GameDataModules::ProfileSplits => create_profile_splits_rows(data, None, extra.clone()),
+            GameDataModules::PbSplits(comparison) => create_profile_splits_rows(data, Some(*comparison), extra.clone()),
             GameDataModules::HomingUsed => create_homing_used_rows(data),
             GameDataModules::DaggersEaten => create_daggers_eaten_rows(data),
             GameDataModules::FarmEfficiency => create_farm_efficiency_rows(data),
+            GameDataModules::Comparison(seconds) => create_comparison_rows(data, *seconds),
+            GameDataModules::Ghost => create_ghost_rows(data, extra.clone()),
             GameDataModules::Spacing | _ => vec![Row::new([""])],
         }
     }
@@ -224,11 +236,84 @@ fn create_collection_accuracy_rows(data: &StatsBlockWithFrames) -> Vec<Row> {
     vec![Row::new([acc_text, acc])]
 }
 
-fn create_homing_splits_rows(
-    data: &StatsBlockWithFrames,
-    times: Vec<(String, f32, i32, i32, u32, Option<i32>)>,
+fn create_profile_splits_rows(data: &StatsBlockWithFrames, comparison: Option<PbComparison>, extra: ExtraSettings) -> Vec<Row> {
+    let cfg = config::cfg();
+    let profile = match config::active_split_profile(&cfg) {
+        Some(profile) => profile,
+        None => return vec![],
+    };
+
+    let reference = comparison.map(|comparison| {
+        let times: Vec<f32> = profile.splits.iter().map(|s| s.1).collect();
+        crate::personal_best::split_references(&data.block.level_hash(), comparison, &times)
+    });
+
+    create_homing_splits_rows(data, profile.splits.clone(), reference.as_deref().map(|r| &r[..]), extra)
+}
+
+#[derive(serde::Serialize, schemars::JsonSchema, Clone, Copy, PartialEq, Debug)]
+pub enum SplitKind {
+    Gold,
+    Positive,
+    Negative,
+    Neutral,
+}
+
+pub struct SplitResult {
+    pub homing: i32,
+    pub diff: Option<i32>,
+    pub kind: SplitKind,
+}
+
+/// Homing at split `i` (or now, if it's the live split) and how it compares
+pub fn evaluate_split(data: &StatsBlockWithFrames, times: &[Split], i: usize, reference: Option<&[SplitReference]>) -> SplitResult {
+    let (_name, time, offset, positive, neutral, gold) = &times[i];
+    let hom = data.get_frame_for_time(*time).map_or(data.frames.last().map_or(0, |x| x.homing), |f| f.homing);
+
+    let last_split_homing = if i == 0 {
+        StatsFrame::default().homing
+    } else {
+        data.get_frame_for_time(times[i - 1].1).map_or(data.block.starting_homing, |f| f.homing)
+    };
+
+    match reference.map(|r| r.get(i).cloned().unwrap_or_default()) {
+        // Compared to the run history: delta to the reference, gold when the segment beats the best one
+        Some(SplitReference { homing: Some(ref_homing), best_segment }) => {
+            let diff = hom - ref_homing;
+            let kind = if best_segment.map_or(false, |best| hom - last_split_homing > best) {
+                SplitKind::Gold
+            } else if diff > 0 {
+                SplitKind::Positive
+            } else if diff < 0 {
+                SplitKind::Negative
+            } else {
+                SplitKind::Neutral
+            };
+            SplitResult { homing: hom, diff: Some(diff), kind }
+        },
+        Some(SplitReference { homing: None, .. }) => SplitResult { homing: hom, diff: None, kind: SplitKind::Neutral },
+        None => {
+            let diff = *offset - (last_split_homing - hom);
+            let kind = if gold.map_or(false, |g| diff >= g) {
+                SplitKind::Gold
+            } else if diff <= *positive + (*neutral as i32) && diff >= *positive - (*neutral as i32) {
+                SplitKind::Neutral
+            } else if diff > *positive {
+                SplitKind::Positive
+            } else {
+                SplitKind::Negative
+            };
+            SplitResult { homing: hom, diff: Some(diff), kind }
+        },
+    }
+}
+
+fn create_homing_splits_rows<'a>(
+    data: &'a StatsBlockWithFrames,
+    times: Vec<Split>,
+    reference: Option<&[SplitReference]>,
     extra: ExtraSettings,
-) -> Vec<Row> {
+) -> Vec<Row<'a>> {
     let real_timer = data.block.time_max + data.block.starting_time;
     let styles = &config::cfg().ui_conf.theming.styles;
     let cfg = crate::config::cfg();
@@ -246,7 +331,7 @@ fn create_homing_splits_rows(
         }
     };
 
-    times.iter().enumerate().filter_map(|(i, (name, time, offset, positive, neutral, gold))| {
+    times.iter().enumerate().filter_map(|(i, (name, time, ..))| {
         let split_text = "   SPLIT";
 
         if data.block.starting_time > *time || (real_timer <= *time && i != current_split_idx) || data.frames.is_empty() {
@@ -264,45 +349,20 @@ fn create_homing_splits_rows(
         }
 
         let time_frame = data.get_frame_for_time(*time);
-        let hom = if let Some(time_frame) = time_frame { time_frame.homing } else { data.frames.last().map_or(0, |x| x.homing) };
         let col = if let Some(time_frame) = time_frame { time_frame.gems_collected } else { data.frames.last().map_or(0, |x| x.gems_collected) };
         let arrow = crate::config::cfg().ui_conf.current_split_marker.clone();
 
-        let v = StatsFrame::default();
-        let last_split_frame = if i == 0 {
-            Some(&v)
-        } else {
-            data.get_frame_for_time(times.get(i-1).unwrap().1)
-        };
-
-        let diff = {
-            if let Some(frame) = last_split_frame {
-                *offset - (frame.homing - hom)
-            } else {
-                *offset - (data.block.starting_homing - hom)
-            }
+        let result = evaluate_split(data, &times, i, reference);
+        let hom = result.homing;
+        let diff = match result.diff {
+            Some(diff) => format!("{:<+4}", diff),
+            None => "-   ".to_string(),
         };
-
-        let split_style = {
-            let mut res = None;
-
-            if let Some(g) = gold {
-                if diff >= *g {
-                    res = Some(styles.split_diff_gold);
-                }
-            }
-
-            if res.is_none() && diff <= *positive + (*neutral as i32) && diff >= *positive - (*neutral as i32) {
-                res = Some(styles.split_diff_neutral);
-            } else if res.is_none() {
-                if diff > *positive {
-                    res = Some(styles.split_diff_pos);
-                } else {
-                    res = Some(styles.split_diff_neg)
-                }
-            }
-
-            res.unwrap()
+        let split_style = match result.kind {
+            SplitKind::Gold => styles.split_diff_gold,
+            SplitKind::Positive => styles.split_diff_pos,
+            SplitKind::Negative => styles.split_diff_neg,
+            SplitKind::Neutral => styles.split_diff_neutral,
         };
 
         let collected = {
@@ -351,7 +411,7 @@ fn create_homing_splits_rows(
             Span::styled(": ".to_string(), styles.text),
             Span::styled(format!("{:>4}", hom), styles.accent),
             Span::styled(" (", styles.text),
-            Span::styled(format!("{:<+4}", diff), split_style),
+            Span::styled(diff, split_style),
             Span::styled(") [", styles.text),
             Span::styled(format!("{:<3}", collected), styles.split_diff_pos),
             Span::styled("-", styles.text),
@@ -399,3 +459,107 @@ fn create_farm_efficiency_rows(data: &StatsBlockWithFrames) -> Vec<Row> {
 
     vec![Row::new([farm_efficiency_text, farm_efficiency])]
 }
+
+/// Green when `delta` is the good direction, enemies alive is better low
+/// Reference value then the delta, 9 columns
+fn delta_spans<'a>(reference: Option<i32>, delta: Option<i32>, lower_is_better: bool) -> [Span<'a>; 2] {
+    let styles = &config::cfg().ui_conf.theming.styles;
+    let reference = match reference {
+        Some(value) => Span::styled(format!("{:>4}", value), styles.accent),
+        None => Span::styled("   -", styles.text),
+    };
+    let delta = match delta {
+        Some(0) => Span::styled(format!(" {:<+4}", 0), styles.split_diff_neutral),
+        Some(d) if (d > 0) != lower_is_better => Span::styled(format!(" {:<+4}", d), styles.split_diff_pos),
+        Some(d) => Span::styled(format!(" {:<+4}", d), styles.split_diff_neg),
+        None => Span::styled("     ", styles.text),
+    };
+    [reference, delta]
+}
+
+fn create_comparison_rows(data: &StatsBlockWithFrames, seconds: usize) -> Vec<Row> {
+    let styles = &config::cfg().ui_conf.theming.styles;
+    let reference = match crate::reference_run::current() {
+        Some(reference) => reference,
+        None => return vec![Row::new([
+            Spans::from(vec![Span::styled("   VS", styles.text)]),
+            Spans::from(vec![Span::styled("no reference ( [F8] best run, [Tab] in [F7] )", styles.text)]),
+        ])],
+    };
+
+    let mut rows = vec![
+        Row::new([
+            Spans::from(vec![Span::styled("   VS", styles.text)]),
+            Spans::from(vec![Span::styled(reference.label.clone(), styles.accent)]),
+        ]),
+        Row::new([
+            Spans::from(vec![Span::styled("", styles.text)]),
+            Spans::from(vec![Span::styled(" HOM      GEM      KIL      ALV", styles.text)]),
+        ]),
+    ];
+
+    for second in crate::reference_run::second_deltas(data, &reference.data, seconds).into_iter().rev() {
+        let (theirs, delta) = (second.reference, second.delta);
+        let spans = [
+            delta_spans(theirs.map(|r| r.homing), delta.map(|d| d.homing), false),
+            delta_spans(theirs.map(|r| r.gems), delta.map(|d| d.gems), false),
+            delta_spans(theirs.map(|r| r.kills), delta.map(|d| d.kills), false),
+            delta_spans(theirs.map(|r| r.enemies_alive), delta.map(|d| d.enemies_alive), true),
+        ];
+        rows.push(Row::new([
+            Spans::from(vec![Span::styled(format!("   {:>4}s", second.time.floor() as i32), styles.text)]),
+            Spans::from(spans.into_iter().flatten().collect::<Vec<_>>()),
+        ]));
+    }
+    rows
+}
+
+fn create_ghost_rows(data: &StatsBlockWithFrames, extra: ExtraSettings) -> Vec<Row> {
+    let styles = &config::cfg().ui_conf.theming.styles;
+    let reference = match crate::reference_run::current() {
+        Some(reference) => reference,
+        None => return vec![],
+    };
+
+    if !extra.ghost_visible || !(data.block.is_replay || data.block.is_in_game) {
+        return vec![];
+    }
+
+    // Same clock as the Timer module
+    let now = if data.block.is_replay { data.block.time } else { data.block.time_max + data.block.starting_time };
+    let mut rows = vec![Row::new([
+        Spans::from(vec![Span::styled("   GHOST", styles.text)]),
+        Spans::from(vec![Span::styled(reference.label.clone(), styles.accent)]),
+    ])];
+
+    let ghost = match crate::reference_run::interpolated_values(&reference.data, now) {
+        Some(ghost) => ghost,
+        None => {
+            let died = reference.data.block.time_max + reference.data.block.starting_time;
+            rows.push(Row::new([
+                Spans::from(vec![Span::styled("", styles.text)]),
+                Spans::from(vec![Span::styled(format!("dead at {:.4}s", died), styles.split_diff_pos)]),
+            ]));
+            return rows;
+        },
+    };
+    let delta = crate::reference_run::diff(crate::reference_run::live_values(data), ghost);
+
+    for (name, value, diff) in [("   G HOMING", ghost.homing, delta.homing), ("   G GEMS", ghost.gems, delta.gems), ("   G KILLS", ghost.kills, delta.kills)] {
+        let diff_style = match diff {
+            0 => styles.split_diff_neutral,
+            d if d > 0 => styles.split_diff_pos,
+            _ => styles.split_diff_neg,
+        };
+        rows.push(Row::new([
+            Spans::from(vec![Span::styled(name, styles.text)]),
+            Spans::from(vec![
+                Span::styled(format!("{:>4}", value), styles.accent),
+                Span::styled(" (", styles.text),
+                Span::styled(format!("{:+}", diff), diff_style),
+                Span::styled(")", styles.text),
+            ]),
+        ]));
+    }
+    rows
+}
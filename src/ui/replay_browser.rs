@@ -0,0 +1,118 @@
+//
+// Replay library screen ( [F7] )
+//
+
+use crossterm::event::KeyCode;
+use tui::{
+    backend::Backend,
+    layout::{Constraint, Rect},
+    text::Span,
+    widgets::{Block, Borders, Row, Table},
+    Frame,
+};
+use crate::replay_library::{self, ReplayEntry};
+use crate::config;
+
+pub enum BrowserAction {
+    /// Path of the replay to play
+    Play(String),
+    /// Library file name to compare against ( Comparison module )
+    Reference(String),
+}
+
+#[derive(Default)]
+pub struct ReplayBrowser {
+    pub open: bool,
+    query: String,
+    selected: usize,
+    entries: Vec<ReplayEntry>,
+}
+
+impl ReplayBrowser {
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        if self.open {
+            self.refresh();
+        }
+    }
+
+    fn refresh(&mut self) {
+        self.entries = replay_library::search(&self.query);
+        self.selected = self.selected.min(self.entries.len().saturating_sub(1));
+    }
+
+    pub fn handle_key(&mut self, key: KeyCode) -> Option<BrowserAction> {
+        match key {
+            KeyCode::Esc | KeyCode::F(7) => self.open = false,
+            KeyCode::Up => self.selected = self.selected.saturating_sub(1),
+            KeyCode::Down => self.selected = (self.selected + 1).min(self.entries.len().saturating_sub(1)),
+            KeyCode::PageUp => self.selected = self.selected.saturating_sub(10),
+            KeyCode::PageDown => self.selected = (self.selected + 10).min(self.entries.len().saturating_sub(1)),
+            KeyCode::Backspace => {
+                self.query.pop();
+                self.refresh();
+            },
+            KeyCode::Char(c) => {
+                self.query.push(c);
+                self.selected = 0;
+                self.refresh();
+            },
+            KeyCode::Enter => {
+                let entry = self.entries.get(self.selected)?;
+                return match replay_library::path_of(&entry.file_name) {
+                    Ok(path) => Some(BrowserAction::Play(path.to_string_lossy().into_owned())),
+                    Err(e) => {
+                        log::warn!("{e:?}");
+                        self.refresh();
+                        None
+                    },
+                };
+            },
+            KeyCode::Tab => {
+                let entry = self.entries.get(self.selected)?;
+                return Some(BrowserAction::Reference(entry.file_name.clone()));
+            },
+            _ => {},
+        }
+        None
+    }
+}
+
+pub fn draw_replay_browser<B>(f: &mut Frame<B>, area: Rect, browser: &ReplayBrowser)
+where
+    B: Backend,
+{
+    let cfg = config::cfg();
+    let styles = &cfg.ui_conf.theming.styles;
+
+    // Keep the selected row on screen, 3 rows go to the border and the header
+    let visible = area.height.saturating_sub(3) as usize;
+    let skip = (browser.selected + 1).saturating_sub(visible);
+
+    let rows = browser.entries.iter().enumerate().skip(skip).take(visible).map(|(i, entry)| {
+        let row = Row::new(vec![
+            replay_library::format_date(entry.date),
+            entry.player_name.clone(),
+            format!("{:.4}", entry.time + entry.start_time),
+            entry.spawnset_hash.chars().take(8).collect(),
+        ]);
+        if i == browser.selected { row.style(styles.most_recent_log) } else { row }
+    });
+
+    let widths = [
+        Constraint::Length(10),
+        Constraint::Percentage(40),
+        Constraint::Length(10),
+        Constraint::Length(8),
+    ];
+
+    let title = format!("Replays [{}] {}/{}  (type to search, Enter plays, Tab compares, Esc closes)", browser.query, browser.entries.len().min(browser.selected + 1), browser.entries.len());
+
+    let t = Table::new(rows)
+        .header(Row::new(vec!["Date", "Player", "Time", "Spawnset"]).style(styles.game_data_title))
+        .widths(&widths)
+        .block(Block::default().borders(Borders::ALL).title(Span::styled(title, styles.game_data_title)))
+        .style(styles.game_data)
+        .column_spacing(1);
+    f.render_widget(t, area);
+}
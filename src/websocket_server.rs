@@ -7,12 +7,10 @@ use ddcore_rs::client_https;
 use ddcore_rs::models::{StatsBlockWithFrames, StatsDataBlock, StatsFrame};
 use futures::SinkExt;
 use futures::{stream::SplitSink, StreamExt};
-use hyper::{Request, Method, Body, Client};
-use serde::{Serialize, Deserialize};
-use serde_json::{Value, json};
+use hyper::{Method, Body, Client};
+use serde_json::Value;
 use tui::style::Color;
 use std::convert::Infallible;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::sync::atomic::AtomicU64;
@@ -26,16 +24,14 @@ use warp::{
 };
 
 use crate::client::ConnectionState;
-use crate::config::{Styles, CONFIG};
 use crate::threads::{AAS, State};
-use crate::ui::modules::GameDataModules;
+use crate::ws_protocol::{ErrorCode, Request, Response, WsRequest, PROTOCOL_VERSION};
+use crate::ws_subscriptions::Subscriptions;
+use crate::server_auth::{self, Access};
+
+/// Frames pushed to every client, outside of any request
+pub type WsBroadcast = Response;
 
-#[derive(Debug, Serialize, Clone)]
-pub struct WsBroadcast {
-    #[serde(rename = "type")]
-    pub _type: String,
-    pub data: String,
-}
 pub struct WebsocketServer;
 
 static LAST_SNOWFLAKE: AtomicU64 = AtomicU64::new(0);
@@ -43,20 +39,27 @@ static LAST_SNOWFLAKE: AtomicU64 = AtomicU64::new(0);
 impl WebsocketServer {
     pub async fn init(state: AAS<State>) {
         tokio::spawn(async move {
-            log::info!("initializing server on port: 13666");
+            let addr = crate::config::cfg().local_server.server_addr();
+            log::info!("initializing server on: {}", addr);
+
+            if server_auth::ensure_token() {
+                let _ = state.load().msg_bus.0.send(crate::threads::Message::SaveCfg);
+            }
 
             let health_check = warp::path("health-check").map(|| "Server OK".to_string());
 
             let ws = warp::path::end()
                 .and(warp::ws())
                 .and(with_state_data(state.clone()))
-                .map(|ws: warp::ws::Ws, state| {
+                .and(server_auth::access())
+                .map(|ws: warp::ws::Ws, state, access| {
                     log::info!("upgrading connection to websocket");
-                    ws.on_upgrade(move |websocket| handle_ws_client(websocket, state))
+                    ws.on_upgrade(move |websocket| handle_ws_client(websocket, state, access))
                 });
 
             let stream = warp::path("miniblock")
                 .and(warp::get())
+                .and(server_auth::read_access())
                 .and(with_state_data(state.clone()))
                 .map(|state: AAS<State>| {
                     let interval = interval(Duration::from_secs_f32(1. / 36.));
@@ -80,26 +83,41 @@ impl WebsocketServer {
                     warp::sse::reply(event_stream)
                 });
 
+            // Origins are checked by server_auth::check_origin, so a page that isn't allowed
+            // gets a 403 instead of a CORS error it could work around with no-cors
             let cors = warp::cors()
                 .allow_any_origin()
-                .allow_headers(vec!["*"])
-                .allow_methods(vec!["POST", "GET"]);
-
-            let routes = health_check
-                .or(ws)
-                .or(stream)
+                .allow_headers(vec!["authorization", "x-ddstats-token", "content-type"])
+                .allow_methods(vec!["POST", "GET", "PUT"]);
+
+            let routes = server_auth::check_origin()
+                .and(health_check
+                    .or(ws)
+                    .or(stream)
+                    .or(crate::overlay::routes(state.clone()))
+                    .or(crate::rest_api::routes(state.clone()))
+                    .or(crate::replay_store::routes()))
+                .recover(server_auth::handle_rejection)
                 .with(cors);
 
-            //let cert = include_bytes!("../tls/certificate.crt");
-            //let key = include_bytes!("../tls/privateKey.key");
+            let tls = crate::config::cfg().local_server.tls.clone();
+            if tls.enabled {
+                match crate::local_tls::load_or_generate(&tls) {
+                    Ok(pair) => {
+                        let tls_addr = crate::config::cfg().local_server.tls_addr();
+                        log::info!("initializing tls server on: {}", tls_addr);
+                        tokio::spawn(warp::serve(routes.clone())
+                            .tls()
+                            .cert(pair.cert)
+                            .key(pair.key)
+                            .run(tls_addr));
+                    },
+                    Err(e) => log::error!("TLS server not started: {:?}", e),
+                }
+            }
+
             warp::serve(routes)
-                //.tls()
-                //.cert(cert)
-                //.key(key)
-                .run(SocketAddr::new(
-                    IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-                    13666,
-                ))
+                .run(addr)
                 .await;
             log::info!("server is running");
         });
@@ -108,16 +126,33 @@ impl WebsocketServer {
 
 async fn handle_ws_client(
     websocket: warp::ws::WebSocket, 
-    state: AAS<State>
+    state: AAS<State>,
+    mut access: Access,
 ) {
     let (mut sender, mut receiver) = websocket.split();
     let mut msg_bus = state.load().msg_bus.0.subscribe();
+    let mut subs = Subscriptions::default();
+    let mut ticker = interval(subs.period());
+    let mut negotiated = false;
 
     loop {
         tokio::select! {
-            msg = msg_bus.recv() => if let Ok(crate::threads::Message::WebSocketMessage(data)) = msg {
-                let t = serde_json::to_string(&data).unwrap();
-                let _ = sender.send(Message::text(t)).await;
+            msg = msg_bus.recv() => {
+                let push = match msg {
+                    Ok(crate::threads::Message::WebSocketMessage(data)) => Some(data),
+                    Ok(crate::threads::Message::Log(line)) => Some(Response::Log(line)),
+                    Ok(crate::threads::Message::NewConnectionState(conn)) => Some(Response::Connection((*conn).clone())),
+                    _ => None,
+                };
+                // Without read access (a LAN peer with no token) nothing is pushed either
+                if let Some(push) = push.filter(|p| access.can_read() && p.topic().map_or(true, |t| subs.has(t))) {
+                    let _ = sender.send(Message::text(push.push_text(negotiated))).await;
+                }
+            },
+            _ = ticker.tick(), if access.can_read() => {
+                for push in subs.poll(&state.load()) {
+                    let _ = sender.send(Message::text(push.push_text(negotiated))).await;
+                }
             },
             body = receiver.next() => {
                 let message = match body {
@@ -129,7 +164,11 @@ async fn handle_ws_client(
                     None => { break; }
                 };
         
-                handle_websocket_message(message, &mut sender, state.clone()).await;
+                let rate = subs.rate();
+                negotiated |= handle_websocket_message(message, &mut sender, state.clone(), &mut subs, &mut access).await;
+                if subs.rate() != rate {
+                    ticker = interval(subs.period());
+                }
             }
         }
     }
@@ -137,137 +176,163 @@ async fn handle_ws_client(
     log::info!("websocket client disconnected");
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct WsRecvMsg {
-    #[serde(rename = "type")]
-    pub _type: String,
-    pub data: Value,
-}
-
+/// True when it answered a hello, pushes use the versioned shape from then on
 async fn handle_websocket_message(
     message: Message,
     sender: &mut SplitSink<WebSocket, Message>,
     data: AAS<State>,
-) {
+    subs: &mut Subscriptions,
+    access: &mut Access,
+) -> bool {
     let msg = if let Ok(s) = message.to_str() {
         s
     } else {
         log::info!("ping-pong");
-        return;
+        return false;
     };
 
-    let msg: WsRecvMsg = if let Ok(s) = serde_json::from_str(msg) {
-        s
-    } else {
-        return;
+    let reply = match serde_json::from_str::<WsRequest>(msg) {
+        Ok(req) => match req.request {
+            // Loading a reference decodes the run history, so it's answered off the runtime
+            Request::SetReference(Some(source)) if access.can_write() => set_reference(&data, source).await.with_id(req.id),
+            request => handle_request(request, &data, subs, access).with_id(req.id),
+        },
+        Err(e) => {
+            // Still echo the id if there was one, so the client can match up the error
+            let id = serde_json::from_str::<Value>(msg).ok().and_then(|v| v.get("id").and_then(Value::as_u64));
+            Response::error(ErrorCode::BadRequest, format!("{e}")).with_id(id)
+        }
     };
 
-    let state = data.load();
-
-    if msg._type.eq("gimme") {
-        let mut v = StatsDto::from_sbwf(state.last_poll.clone());
-        let s = (*state.conn).clone();
-        v.additional_info.connection_state = Some(s);
-        let t = format!("{{\"type\": \"fullblock\", \"data\": {} }}", serde_json::to_string(&v).unwrap());
-        let _ = sender.send(Message::text(t)).await;
-    }
+    let negotiated = matches!(reply.response, Response::Hello { .. });
+    let _ = sender.send(Message::text(serde_json::to_string(&reply).unwrap())).await;
+    negotiated
+}
 
-    if msg._type.eq("config") {
-        let t = serde_json::to_string(crate::config::cfg().as_ref()).unwrap();
-        let t = format!("{{\"type\": \"config\", \"data\": {} }}", t);
-        let _ = sender.send(Message::text(t)).await;
-    }
+fn handle_request(request: Request, data: &AAS<State>, subs: &mut Subscriptions, access: &mut Access) -> Response {
+    let state = data.load();
 
-    if msg._type.eq("version") {
-        let t = format!("{{\"type\": \"version\", \"data\": {} }}", crate::consts::INT_VER);
-        let _ = sender.send(Message::text(t)).await;
+    let allowed = match &request {
+        Request::Hello { .. } => true,
+        req if req.is_mutating() => access.can_write(),
+        _ => access.can_read(),
+    };
+    if !allowed {
+        return Response::error(ErrorCode::Unauthorized, "This request needs the token (hello with a token, or ?token= on connect)");
     }
 
-    if msg._type.eq("ddcl_replay") {
-        let id = msg.data.as_u64().unwrap_or(0) as i32;
-        let cfg = crate::config::cfg();
-        if (*state.conn).eq(&ConnectionState::NotConnected) && cfg.open_game_on_replay_request {
-            log::info!("Opened DD: {:?}", ddcore_rs::memory::start_dd());
-        }
-        let replay_sender = state.msg_bus.0.clone();
-        tokio::spawn(async move {
-            if let Ok(replay) = ddcore_rs::ddinfo::get_replay_by_id(id).await {
-                let _ = replay_sender.send(crate::threads::Message::Replay(Arc::new(replay)));
-            } else {
-                log::warn!("Failed to load DDCL replay {}", id);
+    match request {
+        Request::Hello { version, token } => {
+            if version == 0 || version > PROTOCOL_VERSION {
+                return Response::error(ErrorCode::UnsupportedVersion, format!("Server speaks version {PROTOCOL_VERSION}, got {version}"));
             }
-        });
-        let t = format!("{{\"type\": \"ddcl_replay_ok\", \"data\": {{ \"replay_id\": {} }} }}", id);
-        let _ = sender.send(Message::text(t)).await;
-    }
-
-    if msg._type.eq("replay_link") {
-        let link = msg.data.as_str();
-        if link.is_none() {
-            return;
-        }
-        let link = link.unwrap().to_string();
-        let lc = link.clone();
-        let cfg = crate::config::cfg();
-        if (*state.conn).eq(&ConnectionState::NotConnected) && cfg.open_game_on_replay_request {
-            log::info!("Opened DD: {:?}", ddcore_rs::memory::start_dd());
-        }
-        let replay_sender = state.msg_bus.0.clone();
-        tokio::spawn(async move {
-            if let Ok(replay) = get_replay_link(&link).await {
-                let _ = replay_sender.send(crate::threads::Message::Replay(Arc::new(replay)));
-            } else {
-                log::warn!("Failed to load replay {}", link);
+            if let Some(token) = token {
+                if !server_auth::is_valid_token(&token) {
+                    return Response::error(ErrorCode::Unauthorized, "Wrong token");
+                }
+                access.authorized = true;
             }
-        });
-        let t = format!("{{\"type\": \"replay_link_ok\", \"data\": {{ \"replay_link\": \"{}\" }} }}", lc);
-        let _ = sender.send(Message::text(t)).await;
-    }
-
-    if msg._type.eq("clr-set") {
-        let styles: Result<Styles, serde_json::Error> = serde_json::from_str(&msg.data.to_string());
-        match styles {
-            Ok(styles) => {
-                let mut c = (*CONFIG.load_full()).clone();
-                c.ui_conf.theming.styles = styles;
-                CONFIG.swap(Arc::new(c));
-                let bus_sender = state.msg_bus.0.clone();
-                let _ = bus_sender.send(crate::threads::Message::SaveCfg);
-                let t = "{\"type\": \"color_set_ok\", \"data\": null }".to_string();
-                let _ = sender.send(Message::text(t)).await;
-            },
-            Err(e) => {
-                let t = json!({
-                    "type": "color_set_err",
-                    "data": format!("{e:?}")
-                });
-                let _ = sender.send(Message::text(t.to_string())).await;
+            Response::Hello { version: PROTOCOL_VERSION, server_version: crate::consts::PKG_VERSION.to_owned(), authorized: access.authorized }
+        },
+        Request::Gimme => {
+            let mut v = StatsDto::from_sbwf(state.last_poll.clone());
+            v.additional_info.connection_state = Some((*state.conn).clone());
+            Response::Fullblock(Box::new(v))
+        },
+        Request::Config => Response::Config(crate::server_auth::public_config()),
+        Request::Version => Response::Version(crate::consts::INT_VER),
+        Request::SubmitQueue => Response::SubmitQueue { depth: crate::submit_queue::depth() },
+        Request::DdclReplay(id) => {
+            let cfg = crate::config::cfg();
+            if (*state.conn).eq(&ConnectionState::NotConnected) && cfg.open_game_on_replay_request {
+                log::info!("Opened DD: {:?}", ddcore_rs::memory::start_dd());
             }
-        }
-    }
-
-    if msg._type.eq("set-modules") {
-        let modules: Result<Vec<GameDataModules>, serde_json::Error> = serde_json::from_str(&msg.data.to_string());
-        match modules {
-            Ok(modules) => {
-                let mut c = (*CONFIG.load_full()).clone();
-                c.ui_conf.game_data_modules = modules;
-                CONFIG.swap(Arc::new(c));
-                let bus_sender = state.msg_bus.0.clone();
-                let _ = bus_sender.send(crate::threads::Message::SaveCfg);
-                let t = "{\"type\": \"set_modules_ok\", \"data\": null }".to_string();
-                let _ = sender.send(Message::text(t)).await;
-            },
-            Err(e) => {
-                let t = json!({
-                    "type": "set_modules_err",
-                    "data": format!("{e:?}")
-                });
-                let _ = sender.send(Message::text(t.to_string())).await;
+            let replay_sender = state.msg_bus.0.clone();
+            tokio::spawn(async move {
+                if let Ok(replay) = ddcore_rs::ddinfo::get_replay_by_id(id as i32).await {
+                    let _ = replay_sender.send(crate::threads::Message::Replay(Arc::new(replay)));
+                } else {
+                    log::warn!("Failed to load DDCL replay {}", id);
+                }
+            });
+            Response::DdclReplayOk { replay_id: id }
+        },
+        Request::ReplayLink(link) => {
+            let cfg = crate::config::cfg();
+            if (*state.conn).eq(&ConnectionState::NotConnected) && cfg.open_game_on_replay_request {
+                log::info!("Opened DD: {:?}", ddcore_rs::memory::start_dd());
             }
-        }
+            let replay_sender = state.msg_bus.0.clone();
+            let replay_link = link.clone();
+            tokio::spawn(async move {
+                if let Ok(replay) = crate::replay_store::fetch_link(&link).await {
+                    let _ = replay_sender.send(crate::threads::Message::Replay(Arc::new(replay)));
+                } else {
+                    log::warn!("Failed to load replay {}", link);
+                }
+            });
+            Response::ReplayLinkOk { replay_link }
+        },
+        Request::ClrSet(styles) => {
+            crate::config::update(|c| c.ui_conf.theming.styles = styles);
+            let _ = state.msg_bus.0.send(crate::threads::Message::SaveCfg);
+            Response::ColorSetOk
+        },
+        Request::SetModules(modules) => {
+            crate::config::update(|c| c.ui_conf.game_data_modules = modules);
+            let _ = state.msg_bus.0.send(crate::threads::Message::SaveCfg);
+            Response::SetModulesOk
+        },
+        Request::SplitProfile(name) => match crate::config::select_split_profile(name.as_deref()) {
+            Ok(active) => {
+                let _ = state.msg_bus.0.send(crate::threads::Message::SaveCfg);
+                let _ = state.msg_bus.0.send(crate::threads::Message::Log(format!("Splits: {active}")));
+                let profiles = crate::config::cfg().ui_conf.split_profiles.iter().map(|p| p.name.clone()).collect();
+                Response::SplitProfileOk { active, profiles }
+            },
+            Err(e) => Response::error(ErrorCode::Failed, format!("{e}")),
+        },
+        Request::Schema => Response::Schema(crate::ws_protocol::schema()),
+        Request::Subscribe { topics, rate } => {
+            subs.subscribe(&topics, rate);
+            Response::Subscribed { topics: subs.topics(), rate: subs.rate() }
+        },
+        Request::Unsubscribe { topics } => {
+            subs.unsubscribe(&topics);
+            Response::Subscribed { topics: subs.topics(), rate: subs.rate() }
+        },
+        Request::AckFrames { snowflake, index } => {
+            subs.ack_frames(snowflake, index);
+            Response::FramesAcked { index }
+        },
+        Request::ReplayLibrary { query } => Response::ReplayLibrary {
+            replays: crate::replay_library::search(query.as_deref().unwrap_or_default()),
+        },
+        Request::PlayLibraryReplay(file_name) => match crate::replay_library::path_of(&file_name) {
+            Ok(path) => {
+                let _ = state.msg_bus.0.send(crate::threads::Message::PlayReplayLocalFile(path.to_string_lossy().into_owned()));
+                Response::PlayLibraryReplayOk { file_name }
+            },
+            Err(e) => Response::error(ErrorCode::Failed, format!("{e}")),
+        },
+        Request::SetReference(None) => {
+            crate::reference_run::clear();
+            Response::ReferenceOk { label: None }
+        },
+        Request::SetReference(Some(_)) => unreachable!("answered by set_reference"),
     }
+}
 
+async fn set_reference(data: &AAS<State>, source: crate::reference_run::ReferenceSource) -> Response {
+    let level_hash = data.load().last_poll.block.level_hash();
+    match tokio::task::spawn_blocking(move || crate::reference_run::select(&source, &level_hash)).await {
+        Ok(Ok(label)) => {
+            let _ = data.load().msg_bus.0.send(crate::threads::Message::Log(format!("Comparing against {label}")));
+            Response::ReferenceOk { label: Some(label) }
+        },
+        Ok(Err(e)) => Response::error(ErrorCode::Failed, format!("{e}")),
+        Err(e) => Response::error(ErrorCode::Failed, format!("{e}")),
+    }
 }
 
 struct ColorProxy(pub Color);
@@ -302,7 +367,7 @@ fn with_state_data(c: AAS<State>) -> impl Filter<Extract = (AAS<State>,), Error
     warp::any().map(move || c.clone())
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, schemars::JsonSchema, Clone)]
 pub struct MiniBlock {
     pub time: f32,
     pub daggers_fired: i32,
@@ -325,14 +390,16 @@ pub struct FullDto {
     pub data: StatsBlockWithFrames,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, schemars::JsonSchema, Clone)]
 pub struct StatsDto {
+    #[schemars(with = "crate::ws_protocol::StatsDataBlockSchema")]
     pub block: StatsDataBlock,
+    #[schemars(with = "Vec<crate::ws_protocol::StatsFrameSchema>")]
     pub frames: Vec<StatsFrame>,
     pub additional_info: AdditionalInfo,
 }
 
-#[derive(serde::Serialize)]
+#[derive(serde::Serialize, schemars::JsonSchema, Clone)]
 pub struct AdditionalInfo {
     pub frame_count: usize,
     pub connection_state: Option<ConnectionState>,
@@ -415,7 +482,7 @@ fn sse_miniblock(miniblock: MiniBlock, data: Arc<StatsBlockWithFrames>) -> Resul
 pub async fn get_replay_link(link: &str) -> Result<Vec<u8>> {
     let client = client_https!();
     let uri = link.to_string();
-    let req = Request::builder()
+    let req = hyper::Request::builder()
         .method(Method::GET)
         .uri(uri)
         .body(Body::empty())
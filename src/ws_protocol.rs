@@ -0,0 +1,361 @@
+//
+//  ws_protocol.rs - Typed websocket messages
+//
+//  Every frame is { "id": 1, "type": "...", "data": ... }. "id" is optional,
+//  when a request has one its reply carries the same id. Frames pushed by the
+//  server without a request never have one.
+//
+//  Clients should open with { "type": "hello", "data": { "version": 1 } },
+//  the server answers with the version it speaks or an unsupported_version error.
+//  Until then pushes keep the shape from before versioning (ddstats_game_submit
+//  data is a JSON encoded string), so old dashboards keep working.
+//
+
+use ddcore_rs::models::StatsFrame;
+use schemars::{JsonSchema, schema_for};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::client::ConnectionState;
+use crate::config::Styles;
+use crate::overlay::OverlaySplit;
+use crate::reference_run::ReferenceSource;
+use crate::replay_library::ReplayEntry;
+use crate::replay_upload::UploadStatus;
+use crate::threads::State;
+use crate::ui::modules::GameDataModules;
+use crate::websocket_server::{MiniBlock, StatsDto};
+
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Deserialize, JsonSchema)]
+pub struct WsRequest {
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub request: Request,
+}
+
+#[derive(Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Request {
+    /// `token` unlocks requests that change settings or load replays
+    Hello { version: u32, #[serde(default)] token: Option<String> },
+    Gimme,
+    Config,
+    Version,
+    SubmitQueue,
+    DdclReplay(u64),
+    ReplayLink(String),
+    #[serde(rename = "clr-set")]
+    ClrSet(#[schemars(with = "StylesSchema")] Styles),
+    #[serde(rename = "set-modules")]
+    SetModules(Vec<GameDataModules>),
+    /// Profile name, or null for the next one
+    #[serde(rename = "split-profile")]
+    SplitProfile(Option<String>),
+    Schema,
+    /// Polled topics are pushed at `rate` per second (1-60, default 10), only when they change
+    Subscribe { topics: Vec<Topic>, #[serde(default)] rate: Option<f32> },
+    Unsubscribe { topics: Vec<Topic> },
+    /// Client has the first `index` frames of run `snowflake`, frame_history continues from there
+    AckFrames { snowflake: u128, index: usize },
+    /// Replays in the library matching `query` (player, file name or spawnset hash), newest first
+    ReplayLibrary { #[serde(default)] query: Option<String> },
+    /// File name from replay_library
+    PlayLibraryReplay(String),
+    /// Run the Comparison module measures against, null clears it
+    SetReference(Option<ReferenceSource>),
+}
+
+impl Request {
+    /// Changes settings or makes the client fetch and play something
+    pub fn is_mutating(&self) -> bool {
+        matches!(self,
+            Request::DdclReplay(_)
+            | Request::ReplayLink(_)
+            | Request::ClrSet(_)
+            | Request::SetModules(_)
+            | Request::SplitProfile(_)
+            | Request::PlayLibraryReplay(_)
+            | Request::SetReference(_))
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    /// Live game values (frame)
+    Frames,
+    /// Game status, player and run changes (status)
+    Status,
+    /// Submitted runs and the submit queue (ddstats_game_submit, submit_queue)
+    Submissions,
+    /// Log lines shown in the UI (log)
+    Logs,
+    /// Game connection state (connection)
+    Connection,
+    /// Active split profile with the current run's splits (splits)
+    Splits,
+    /// Per second frames of the current run, only the new ones (frames_reset, frames_append)
+    FrameHistory,
+    /// Progress of replay uploads (replay_upload)
+    Uploads,
+}
+
+#[derive(Serialize, JsonSchema, Clone, PartialEq, Debug)]
+pub struct LiveStatus {
+    pub game_status: i32,
+    pub is_replay: bool,
+    pub player_name: String,
+    pub level_hash: String,
+    pub snowflake: u128,
+}
+
+impl LiveStatus {
+    pub fn from_state(state: &State) -> Self {
+        let block = &state.last_poll.block;
+        Self {
+            game_status: block.status,
+            is_replay: block.is_replay,
+            player_name: block.player_username(),
+            level_hash: block.level_hash(),
+            snowflake: *state.snowflake,
+        }
+    }
+}
+
+#[derive(Serialize, JsonSchema, Clone)]
+pub struct WsResponse {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub response: Response,
+}
+
+#[derive(Serialize, JsonSchema, Clone)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Response {
+    Hello { version: u32, server_version: String, authorized: bool },
+    Fullblock(Box<StatsDto>),
+    Config(Value),
+    Version(u32),
+    SubmitQueue { depth: usize },
+    DdclReplayOk { replay_id: u64 },
+    ReplayLinkOk { replay_link: String },
+    ColorSetOk,
+    SetModulesOk,
+    SplitProfileOk { active: String, profiles: Vec<String> },
+    Schema(Value),
+    DdstatsGameSubmit { game_id: u32, snowflake: u64 },
+    Subscribed { topics: Vec<Topic>, rate: f32 },
+    Frame(MiniBlock),
+    Status(LiveStatus),
+    Splits { profile: Option<String>, splits: Vec<OverlaySplit> },
+    Log(String),
+    Connection(ConnectionState),
+    /// A new run started, drop every frame kept so far
+    FramesReset { snowflake: u128 },
+    /// Frames `start..start + frames.len()` of the run
+    FramesAppend { snowflake: u128, start: usize, #[schemars(with = "Vec<StatsFrameSchema>")] frames: Vec<StatsFrame> },
+    FramesAcked { index: usize },
+    ReplayLibrary { replays: Vec<ReplayEntry> },
+    PlayLibraryReplayOk { file_name: String },
+    ReferenceOk { label: Option<String> },
+    ReplayUpload(UploadStatus),
+    Error { code: ErrorCode, message: String },
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// Not JSON, unknown type or data of the wrong shape
+    BadRequest,
+    UnsupportedVersion,
+    /// Origin not on the allowlist, or the request needs the token
+    Unauthorized,
+    /// The request was understood but couldn't be carried out
+    Failed,
+}
+
+impl Response {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> Self {
+        Response::Error { code, message: message.into() }
+    }
+
+    pub fn with_id(self, id: Option<u64>) -> WsResponse {
+        WsResponse { id, response: self }
+    }
+
+    /// Text of a pushed frame, in the unversioned shape for clients that never sent hello
+    pub fn push_text(self, negotiated: bool) -> String {
+        match self {
+            Response::DdstatsGameSubmit { game_id, snowflake } if !negotiated => serde_json::json!({
+                "type": "ddstats_game_submit",
+                "data": format!("{{ \"game_id\": {game_id}, \"snowflake\": {snowflake} }}"),
+            }).to_string(),
+            push => serde_json::to_string(&push.with_id(None)).unwrap(),
+        }
+    }
+
+    /// Topic a pushed frame belongs to, None for replies
+    pub fn topic(&self) -> Option<Topic> {
+        match self {
+            Response::SubmitQueue { .. } | Response::DdstatsGameSubmit { .. } => Some(Topic::Submissions),
+            Response::Frame(_) => Some(Topic::Frames),
+            Response::Status(_) => Some(Topic::Status),
+            Response::Splits { .. } => Some(Topic::Splits),
+            Response::Log(_) => Some(Topic::Logs),
+            Response::Connection(_) => Some(Topic::Connection),
+            Response::FramesReset { .. } | Response::FramesAppend { .. } => Some(Topic::FrameHistory),
+            Response::ReplayUpload(_) => Some(Topic::Uploads),
+            _ => None,
+        }
+    }
+}
+
+//
+// Schema only mirrors of the ddcore-rs and tui types sent as is, they must serialize the same
+//
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub struct StatsFrameSchema {
+    pub gems_collected: i32,
+    pub kills: i32,
+    pub daggers_fired: i32,
+    pub daggers_hit: i32,
+    pub enemies_alive: i32,
+    pub level_gems: i32,
+    pub homing: i32,
+    pub gems_despawned: i32,
+    pub gems_eaten: i32,
+    pub gems_total: i32,
+    pub daggers_eaten: i32,
+    pub per_enemy_alive_count: [i16; 17],
+    pub per_enemy_kill_count: [i16; 17],
+}
+
+/// Raw game memory, `username`, `replay_player_name` and `survival_md5` are byte arrays
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub struct StatsDataBlockSchema {
+    pub marker: [u8; 11],
+    pub ddstats_version: i32,
+    pub player_id: i32,
+    pub username: [u8; 32],
+    pub time: f32,
+    pub gems_collected: i32,
+    pub kills: i32,
+    pub daggers_fired: i32,
+    pub daggers_hit: i32,
+    pub enemies_alive: i32,
+    pub level_gems: i32,
+    pub homing: i32,
+    pub gems_despawned: i32,
+    pub gems_eaten: i32,
+    pub gems_total: i32,
+    pub daggers_eaten: i32,
+    pub per_enemy_alive_count: [i16; 17],
+    pub per_enemy_kill_count: [i16; 17],
+    pub is_player_alive: bool,
+    pub is_replay: bool,
+    pub death_type: u8,
+    pub is_in_game: bool,
+    pub replay_player_id: i32,
+    pub replay_player_name: [u8; 32],
+    pub survival_md5: [u8; 16],
+    pub time_lvl2: f32,
+    pub time_lvl3: f32,
+    pub time_lvl4: f32,
+    pub levi_down_time: f32,
+    pub orb_down_time: f32,
+    pub status: i32,
+    pub max_homing: i32,
+    pub time_max_homing: f32,
+    pub enemies_alive_max: i32,
+    pub time_enemies_alive_max: f32,
+    pub time_max: f32,
+    pub padding1: [u8; 4],
+    pub stats_base: [u8; 8],
+    pub stats_frames_loaded: i32,
+    pub stats_finished_loading: bool,
+    pub padding2: [u8; 3],
+    pub starting_hand: i32,
+    pub starting_homing: i32,
+    pub starting_time: f32,
+    pub prohibited_mods: bool,
+    pub padding3: [u8; 3],
+    pub replay_base: [u8; 8],
+    pub replay_buffer_length: i32,
+    pub replay_flag: bool,
+    pub game_mode: u8,
+    pub is_time_attack_or_race_finished: bool,
+}
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub enum ColorSchema {
+    Reset,
+    Black,
+    Red,
+    Green,
+    Yellow,
+    Blue,
+    Magenta,
+    Cyan,
+    Gray,
+    DarkGray,
+    LightRed,
+    LightGreen,
+    LightYellow,
+    LightBlue,
+    LightMagenta,
+    LightCyan,
+    White,
+    Rgb(u8, u8, u8),
+    Indexed(u8),
+}
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub struct ModifierSchema {
+    /// BOLD 1, DIM 2, ITALIC 4, UNDERLINED 8, SLOW_BLINK 16, RAPID_BLINK 32, REVERSED 64, HIDDEN 128, CROSSED_OUT 256
+    pub bits: u16,
+}
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub struct StyleSchema {
+    pub fg: Option<ColorSchema>,
+    pub bg: Option<ColorSchema>,
+    pub add_modifier: ModifierSchema,
+    pub sub_modifier: ModifierSchema,
+}
+
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+pub struct StylesSchema {
+    pub text: StyleSchema,
+    pub logo: StyleSchema,
+    pub logs: StyleSchema,
+    pub logs_title: StyleSchema,
+    pub log_text: StyleSchema,
+    pub most_recent_log: StyleSchema,
+    pub game_data: StyleSchema,
+    pub game_data_title: StyleSchema,
+    pub split_name: StyleSchema,
+    pub accent: StyleSchema,
+    pub split_diff_pos: StyleSchema,
+    pub split_diff_neg: StyleSchema,
+    pub split_diff_neutral: StyleSchema,
+    pub split_diff_gold: StyleSchema,
+}
+
+/// JSON schema of both directions, for checking dashboard code against
+pub fn schema() -> Value {
+    serde_json::json!({
+        "protocol_version": PROTOCOL_VERSION,
+        "request": schema_for!(WsRequest),
+        "response": schema_for!(WsResponse),
+    })
+}
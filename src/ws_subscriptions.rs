@@ -0,0 +1,222 @@
+//
+//  ws_subscriptions.rs - Per client topic subscriptions for the websocket server
+//
+
+use std::collections::HashSet;
+use std::time::Duration;
+use crate::threads::State;
+use crate::websocket_server::MiniBlock;
+use crate::ws_protocol::{LiveStatus, Response, Topic};
+
+const DEFAULT_RATE: f32 = 10.;
+const MAX_RATE: f32 = 60.;
+
+pub struct Subscriptions {
+    topics: HashSet<Topic>,
+    rate: f32,
+    // Last pushed value of every polled topic, so only changes go out
+    last_frame: Option<String>,
+    last_status: Option<LiveStatus>,
+    last_splits: Option<String>,
+    frame_history: FrameHistory,
+}
+
+/// Where the frame_history topic is at for this client
+#[derive(Default)]
+struct FrameHistory {
+    snowflake: Option<u128>,
+    sent: usize,
+}
+
+impl Default for Subscriptions {
+    /// Clients that never subscribe keep getting what was broadcast before topics existed
+    fn default() -> Self {
+        Self {
+            topics: HashSet::from([Topic::Submissions]),
+            rate: DEFAULT_RATE,
+            last_frame: None,
+            last_status: None,
+            last_splits: None,
+            frame_history: FrameHistory::default(),
+        }
+    }
+}
+
+impl Subscriptions {
+    pub fn subscribe(&mut self, topics: &[Topic], rate: Option<f32>) {
+        self.topics.extend(topics.iter().copied());
+        if let Some(rate) = rate {
+            self.rate = rate.clamp(1., MAX_RATE);
+        }
+    }
+
+    pub fn unsubscribe(&mut self, topics: &[Topic]) {
+        for topic in topics {
+            self.topics.remove(topic);
+            match topic {
+                Topic::Frames => self.last_frame = None,
+                Topic::Status => self.last_status = None,
+                Topic::Splits => self.last_splits = None,
+                Topic::FrameHistory => self.frame_history = FrameHistory::default(),
+                _ => {},
+            }
+        }
+    }
+
+    /// Resends everything after `index` if the client is behind, acks for older runs are ignored
+    pub fn ack_frames(&mut self, snowflake: u128, index: usize) {
+        if self.frame_history.snowflake == Some(snowflake) && index < self.frame_history.sent {
+            self.frame_history.sent = index;
+        }
+        if self.frame_history.snowflake.is_none() {
+            // Resuming after a reconnect, skip the reset if it's still the same run
+            self.frame_history = FrameHistory { snowflake: Some(snowflake), sent: index };
+        }
+    }
+
+    pub fn topics(&self) -> Vec<Topic> {
+        let mut topics: Vec<Topic> = self.topics.iter().copied().collect();
+        topics.sort();
+        topics
+    }
+
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    pub fn period(&self) -> Duration {
+        Duration::from_secs_f32(1. / self.rate)
+    }
+
+    pub fn has(&self, topic: Topic) -> bool {
+        self.topics.contains(&topic)
+    }
+
+    /// Pushes for the polled topics that changed since the last tick
+    pub fn poll(&mut self, state: &State) -> Vec<Response> {
+        let mut out = vec![];
+
+        if self.has(Topic::Frames) {
+            let mini = MiniBlock::from_stats(state.last_poll.clone(), state.snowflake.clone());
+            let encoded = serde_json::to_string(&mini).unwrap();
+            if self.last_frame.as_ref() != Some(&encoded) {
+                self.last_frame = Some(encoded);
+                out.push(Response::Frame(mini));
+            }
+        }
+
+        if self.has(Topic::Status) {
+            let status = LiveStatus::from_state(state);
+            if self.last_status.as_ref() != Some(&status) {
+                self.last_status = Some(status.clone());
+                out.push(Response::Status(status));
+            }
+        }
+
+        if self.has(Topic::Splits) {
+            let (profile, splits) = crate::overlay::current_splits(state, None);
+            let encoded = serde_json::to_string(&splits).unwrap();
+            if self.last_splits.as_ref() != Some(&encoded) {
+                self.last_splits = Some(encoded);
+                out.push(Response::Splits { profile, splits });
+            }
+        }
+
+        if self.has(Topic::FrameHistory) {
+            let frames = &state.last_poll.frames;
+            let snowflake = *state.snowflake;
+            let history = &mut self.frame_history;
+
+            if history.snowflake != Some(snowflake) || frames.len() < history.sent {
+                *history = FrameHistory { snowflake: Some(snowflake), sent: 0 };
+                out.push(Response::FramesReset { snowflake });
+            }
+
+            if frames.len() > history.sent {
+                out.push(Response::FramesAppend { snowflake, start: history.sent, frames: frames[history.sent..].to_vec() });
+                history.sent = frames.len();
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use ddcore_rs::models::StatsBlockWithFrames;
+    use super::*;
+
+    fn state(snowflake: u128, frames: usize) -> State {
+        State {
+            conn: Arc::default(),
+            last_poll: Arc::new(StatsBlockWithFrames { frames: vec![Default::default(); frames], ..Default::default() }),
+            snowflake: Arc::new(snowflake),
+            msg_bus: Arc::new(tokio::sync::broadcast::channel(1)),
+        }
+    }
+
+    fn history() -> Subscriptions {
+        let mut subs = Subscriptions::default();
+        subs.subscribe(&[Topic::FrameHistory], None);
+        subs
+    }
+
+    /// (reset, first appended index, appended count)
+    fn summary(pushes: &[Response]) -> (bool, Option<usize>, usize) {
+        let reset = pushes.iter().any(|p| matches!(p, Response::FramesReset { .. }));
+        match pushes.iter().find_map(|p| match p { Response::FramesAppend { start, frames, .. } => Some((*start, frames.len())), _ => None }) {
+            Some((start, count)) => (reset, Some(start), count),
+            None => (reset, None, 0),
+        }
+    }
+
+    #[test]
+    fn new_run_resets_then_appends_only_new_frames() {
+        let mut subs = history();
+
+        assert_eq!(summary(&subs.poll(&state(1, 5))), (true, Some(0), 5));
+        assert_eq!(summary(&subs.poll(&state(1, 5))), (false, None, 0));
+        assert_eq!(summary(&subs.poll(&state(1, 7))), (false, Some(5), 2));
+        assert_eq!(summary(&subs.poll(&state(2, 1))), (true, Some(0), 1));
+    }
+
+    #[test]
+    fn ack_behind_rewinds_to_the_acked_index() {
+        let mut subs = history();
+        subs.poll(&state(1, 5));
+
+        subs.ack_frames(1, 2);
+
+        assert_eq!(summary(&subs.poll(&state(1, 5))), (false, Some(2), 3));
+    }
+
+    #[test]
+    fn ack_ahead_or_for_another_run_is_ignored() {
+        let mut subs = history();
+        subs.poll(&state(1, 5));
+
+        subs.ack_frames(1, 9);
+        subs.ack_frames(7, 0);
+
+        assert_eq!(summary(&subs.poll(&state(1, 6))), (false, Some(5), 1));
+    }
+
+    #[test]
+    fn ack_before_the_first_poll_resumes_without_a_reset() {
+        let mut subs = history();
+
+        subs.ack_frames(1, 3);
+
+        assert_eq!(summary(&subs.poll(&state(1, 5))), (false, Some(3), 2));
+    }
+
+    #[test]
+    fn fewer_frames_than_sent_resets() {
+        let mut subs = history();
+        subs.poll(&state(1, 5));
+
+        assert_eq!(summary(&subs.poll(&state(1, 2))), (true, Some(0), 2));
+    }
+}